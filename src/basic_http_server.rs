@@ -1,63 +1,883 @@
-use color_eyre::eyre::{eyre, Result, OptionExt};
 use tracing::*;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tokio::fs::File;
-use bytes::BytesMut;
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use bytes::{Buf, Bytes, BytesMut};
+use flate2::{write::{DeflateEncoder, GzEncoder, ZlibEncoder}, Compression};
+use memmap2::Mmap;
+use std::future::Future;
+use std::io::Write as _;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+use crate::body::{Body, BodyLength, BodyReader};
+use crate::digest::{base64, Md5};
+use crate::error::{Result, ServerError};
+use crate::file_source::{resolve_path, Disk, FileInfo, FileReader, FileSource, Limited};
+use crate::metrics::Metrics;
+use crate::multipart::{self, Multipart};
+use crate::path_locks::PathLocks;
+use crate::{http_date, json, mime, uri};
+use crate::rewound::Rewound;
+use crate::throttle::{self, Throttle};
+use crate::upgrade::Upgraded;
+use crate::websocket;
+use crate::server_config::{strip_path_prefix, ServerConfig};
+
+/// Files at least this large are gzip-compressed on the fly while being
+/// streamed instead of being sent uncompressed from memory.
+const GZIP_STREAM_MIN_LEN: u64 = 1 << 20;
+
+/// Bodies shorter than this aren't worth compressing, the compressed
+/// format's own framing would outweigh the savings.
+const COMPRESS_MIN_LEN: usize = 150;
+
+/// Bounds of the pause after an accept error the listener may recover
+/// from, doubled on each error in a row.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Target of the one line logged for each request served, so that these
+/// can be told apart from the rest of the logs.
+pub const ACCESS_LOG_TARGET: &str = "access";
+
+/// How long the rest of a refused request is read and thrown away before
+/// the connection is closed. Closing with unread input resets the
+/// connection, which can lose the refusal before the client reads it.
+const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Bytes logged from the start of each upload's body with `--dump-bodies`.
+const DUMP_BODY_LEN: usize = 256;
+
+/// Longest pause `/delay/<secs>` will take, so that a single request
+/// can't hold a connection open indefinitely.
+const MAX_DELAY_SECS: u64 = 60;
+
+/// Headers about a single connection rather than the message, which the
+/// server deals with itself.
+const HOP_BY_HOP: [&str; 7] = [
+    "connection", "keep-alive", "proxy-connection", "te", "trailer", "transfer-encoding", "upgrade",
+];
+
+/// Connection preface sent first by HTTP/2 clients with prior knowledge.
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Custom handler for POST requests, called with the request path and its
+/// body as it streams in.
+pub type PostHandler = Arc<dyn Fn(String, Body)
+                           -> Pin<Box<dyn Future<Output = http::Response<Vec<u8>>> + Send>>
+                           + Send + Sync>;
+
+/// Custom handler for requests with a given method under a path prefix,
+/// called with the request, whose body streams in as the handler reads it.
+pub type RouteHandler = Arc<dyn Fn(http::Request<Body>)
+                            -> Pin<Box<dyn Future<Output = http::Response<Vec<u8>>> + Send>>
+                            + Send + Sync>;
+
+/// Custom handler taking over connections that switch protocols, called
+/// with the request path and the connection.
+pub type UpgradeHandler = Arc<dyn Fn(String, Upgraded)
+                              -> Pin<Box<dyn Future<Output = ()> + Send>>
+                              + Send + Sync>;
+
+/// A route handler along with the requests it's for.
+#[derive(Clone)]
+struct Route {
+    method: String,
+    prefix: String,
+    handler: RouteHandler,
+}
 
 pub struct BasicHttpServer {
     listener: TcpListener,
-    dir: String,
+    config: ServerConfig,
+    routes: Vec<Route>,
+    post_handlers: Vec<(String, PostHandler)>,
+    upgrade_handlers: Vec<(String, UpgradeHandler)>,
+    files: Arc<dyn FileSource>,
+}
+
+/// State shared by all connection handlers.
+struct SharedState {
+    config: ServerConfig,
+    routes: Vec<Route>,
+    post_handlers: Vec<(String, PostHandler)>,
+    upgrade_handlers: Vec<(String, UpgradeHandler)>,
+    files: Arc<dyn FileSource>,
+    /// Open files allowed beyond those read through `files`, which are
+    /// limited by the same semaphore.
+    open_files: Option<Arc<Semaphore>>,
+    /// Requests seen so far, counted against `max_total_requests`.
+    requests: AtomicU64,
+    metrics: Arc<Metrics>,
+    /// Set once no more requests are to be served after those in flight.
+    shutdown: watch::Sender<bool>,
+    /// Held by conditional uploads to a file from checking their
+    /// preconditions until it's written, so that only one of several
+    /// racing ones wins.
+    write_locks: PathLocks,
+}
+
+impl SharedState {
+    /// The handler of the route for `method` with the longest prefix of
+    /// `path`.
+    fn route(&self, method: &str, path: &str) -> Option<&RouteHandler> {
+        self.routes
+            .iter()
+            .filter(|route| route.method == method && strip_path_prefix(path, &route.prefix).is_some())
+            .max_by_key(|route| route.prefix.len())
+            .map(|route| &route.handler)
+    }
+
+    fn post_handler(&self, path: &str) -> Option<&PostHandler> {
+        self.post_handlers
+            .iter()
+            .filter(|(prefix, _)| strip_path_prefix(path, prefix).is_some())
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, handler)| handler)
+    }
+
+    /// The first protocol in an `Upgrade` header that has a handler, along
+    /// with the handler.
+    fn upgrade_handler(&self, upgrade: &str) -> Option<(&str, &UpgradeHandler)> {
+        upgrade.split(',')
+            .map(str::trim)
+            .find_map(|protocol| {
+                self.upgrade_handlers
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(protocol))
+            })
+            .map(|(name, handler)| (name.as_str(), handler))
+    }
+
+    /// Waits until another file may be opened, if open files are limited.
+    async fn open_file_permit(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.open_files {
+            Some(permits) => permits.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+
+    /// Counts a request, starting the shutdown once it's the last one the
+    /// server is to serve.
+    fn count_request(&self) {
+        let served = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.config.max_total_requests.is_some_and(|max| served >= max)
+            && !self.shutdown.send_replace(true) {
+            info!("served {served} requests, shutting down");
+        }
+    }
+
+    fn shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
 }
 
 enum HttpEncoding {
     Gzip,
+    /// zlib-wrapped DEFLATE, as RFC 9110 defines `deflate`.
+    Deflate,
+    /// Bare DEFLATE data sent as `deflate`, which some older clients
+    /// expect instead.
+    DeflateRaw,
+}
+
+/// Encodings a file may be found stored in, next to the plain file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Precompressed {
+    Brotli,
+    Gzip,
+}
+
+impl Precompressed {
+    fn name(self) -> &'static str {
+        match self {
+            Precompressed::Brotli => "br",
+            Precompressed::Gzip => "gzip",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Precompressed::Brotli => ".br",
+            Precompressed::Gzip => ".gz",
+        }
+    }
+}
+
+/// The ways a file's body may be encoded for the client.
+struct FileCodings<'a> {
+    encoding: &'a Option<HttpEncoding>,
+    precompressed: &'a [Precompressed],
+    te_gzip: bool,
+}
+
+/// A response body written after the headers rather than held in the
+/// response.
+enum FileBody {
+    /// Gzip-compressed as it's read, and sent with chunked framing.
+    Gzip(FileReader),
+    /// Written out as is from the file mapped into memory.
+    Mapped(Mmap),
+}
+
+/// Body format of error responses, negotiated from the Accept header.
+#[derive(Clone, Copy)]
+enum ErrorFormat {
+    Json,
+    Text,
+}
+
+/// The parts of a GET request that decide its response.
+struct GetRequest {
+    path: String,
+    host: Option<String>,
+    query: Option<String>,
+    ua: Option<String>,
+    encoding: Option<HttpEncoding>,
+    /// Stored encodings the client accepts, most preferred first.
+    precompressed: Vec<Precompressed>,
+    /// The `TE` header accepts gzip, so a body may be compressed hop by hop
+    /// rather than end to end.
+    te_gzip: bool,
+    /// The `TE` header accepts trailers after a chunked body.
+    te_trailers: bool,
+    error_format: ErrorFormat,
+    conditions: Conditions,
+}
+
+/// The parts of a POST or PUT request that decide where its body goes.
+struct PostRequest {
+    /// A PUT rather than a POST.
+    put: bool,
+    path: String,
+    host: Option<String>,
+    range: Option<ContentRange>,
+    conditions: WriteConditions,
+    content_type: Option<String>,
+    error_format: ErrorFormat,
+}
+
+/// A request being answered on an HTTP/1 connection, with what it takes
+/// to read its body or refuse it.
+struct Exchange<'a> {
+    stream: &'a mut TcpStream,
+    buf: &'a mut BytesMut,
+    config: &'a ServerConfig,
+    version: http::Version,
+    close: bool,
+    error_format: ErrorFormat,
+    /// Where the body starts in `buf`, until it's been read from.
+    body_offset: usize,
+    body_len: BodyLength,
+}
+
+/// A response to send on a connection still in a state to carry it.
+struct Reply {
+    resp: http::Response<Vec<u8>>,
+    close: bool,
+    /// Set when the body is streamed from a file after the headers rather
+    /// than being part of `resp`.
+    file_body: Option<FileBody>,
+    checksum: Option<Checksum>,
+}
+
+/// Digest sent in the `Content-MD5` trailer of a streamed body. It covers
+/// the body as sent when that's content coded, and the file as read when
+/// the coding is only for transfer.
+struct Checksum {
+    md5: Md5,
+    encoded: bool,
+}
+
+/// Conditional and range request headers, evaluated against a file's
+/// validators and length.
+#[derive(Default)]
+struct Conditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    range: Option<String>,
+}
+
+/// What a `Range` header asks of a file of a given length.
+enum RangeRequest {
+    /// Not a single byte range, the whole file is sent.
+    Ignored,
+    /// Inclusive bounds of the bytes to send.
+    Satisfiable(u64, u64),
+    /// Starts past the end of the file.
+    Unsatisfiable,
+}
+
+/// Preconditions on the current file an upload may replace.
+#[derive(Default)]
+struct WriteConditions {
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+}
+
+impl WriteConditions {
+    fn is_empty(&self) -> bool {
+        self.if_match.is_none() && self.if_none_match.is_none()
+    }
+}
+
+/// Region of a file written by a PUT carrying `Content-Range`, with
+/// `end` inclusive.
+struct ContentRange {
+    start: u64,
+    end: u64,
+    total: u64,
+}
+
+/// A request to switch to another protocol on the connection.
+struct UpgradeRequest {
+    protocol: String,
+    websocket_key: Option<String>,
+}
+
+/// What a CORS preflight request asks to be allowed.
+struct Preflight {
+    method: String,
+    headers: Option<String>,
 }
 
 enum ParseResult {
+    /// A GET, or a HEAD when `head` is set.
     Get {
+        version: http::Version,
         close: bool,
-        path: String,
-        ua: Option<String>,
-        encoding: Option<HttpEncoding>,
+        forwarded_for: Vec<IpAddr>,
+        head: bool,
+        upgrade: Option<UpgradeRequest>,
+        body_offset: usize,
+        /// A body on a GET has no meaning, but it's still read off the
+        /// connection so that the next request starts where it should.
+        body_len: BodyLength,
+        request: GetRequest,
     },
+    /// An upload by POST, or by PUT when the request's `put` is set.
     Post {
+        version: http::Version,
         close: bool,
+        forwarded_for: Vec<IpAddr>,
+        body_offset: usize,
+        body_len: BodyLength,
+        request: PostRequest,
+    },
+    Other {
+        method: String,
         path: String,
+        version: http::Version,
+        close: bool,
+        forwarded_for: Vec<IpAddr>,
+        preflight: Option<Preflight>,
         body_offset: usize,
-        body_len: usize,
+        body_len: BodyLength,
+        error_format: ErrorFormat,
+    },
+    /// Refused outright. The connection is closed after the response, as
+    /// the request's framing can't be trusted.
+    Invalid {
+        status: http::StatusCode,
+        error_format: ErrorFormat,
+    },
+}
+
+impl ParseResult {
+    /// Version the response is sent with, the request's own where known.
+    fn version(&self) -> http::Version {
+        match self {
+            ParseResult::Get { version, .. }
+            | ParseResult::Post { version, .. }
+            | ParseResult::Other { version, .. } => *version,
+            ParseResult::Invalid { .. } => http::Version::HTTP_11,
+        }
+    }
+
+    /// Addresses the request passed through according to its forwarding
+    /// headers, the original client first.
+    fn forwarded_for(&self) -> &[IpAddr] {
+        match self {
+            ParseResult::Get { forwarded_for, .. }
+            | ParseResult::Post { forwarded_for, .. }
+            | ParseResult::Other { forwarded_for, .. } => forwarded_for,
+            ParseResult::Invalid { .. } => &[],
+        }
+    }
+
+    fn method(&self) -> Option<&str> {
+        match self {
+            ParseResult::Get { head, .. } => Some(if *head { "HEAD" } else { "GET" }),
+            ParseResult::Post { request, .. } => Some(if request.put { "PUT" } else { "POST" }),
+            ParseResult::Other { method, .. } => Some(method),
+            ParseResult::Invalid { .. } => None,
+        }
+    }
+
+    /// The request as one whose method isn't allowed, to be answered with
+    /// a 405 once its body has been read.
+    fn into_not_allowed(self) -> ParseResult {
+        let method = self.method().unwrap_or_default().to_string();
+        match self {
+            ParseResult::Get { version, close, forwarded_for, body_offset, body_len, request, .. } => {
+                ParseResult::Other {
+                    method,
+                    path: request.path,
+                    version,
+                    close,
+                    forwarded_for,
+                    preflight: None,
+                    body_offset,
+                    body_len,
+                    error_format: request.error_format,
+                }
+            }
+            ParseResult::Post { version, close, forwarded_for, body_offset, body_len, request } => {
+                ParseResult::Other {
+                    method,
+                    path: request.path,
+                    version,
+                    close,
+                    forwarded_for,
+                    preflight: None,
+                    body_offset,
+                    body_len,
+                    error_format: request.error_format,
+                }
+            }
+            parse_res => parse_res,
+        }
+    }
+
+    /// Whether the connection is to be closed after the response.
+    fn close(&self) -> bool {
+        match self {
+            ParseResult::Get { close, .. }
+            | ParseResult::Post { close, .. }
+            | ParseResult::Other { close, .. } => *close,
+            ParseResult::Invalid { .. } => true,
+        }
+    }
+
+    /// Where the request's body starts in the buffer.
+    fn body_offset(&self) -> usize {
+        match self {
+            ParseResult::Get { body_offset, .. }
+            | ParseResult::Post { body_offset, .. }
+            | ParseResult::Other { body_offset, .. } => *body_offset,
+            ParseResult::Invalid { .. } => 0,
+        }
+    }
+
+    /// How the request's body is framed, where it got far enough to tell.
+    fn body_len(&self) -> Option<BodyLength> {
+        match self {
+            ParseResult::Get { body_len, .. }
+            | ParseResult::Post { body_len, .. }
+            | ParseResult::Other { body_len, .. } => Some(*body_len),
+            ParseResult::Invalid { .. } => None,
+        }
+    }
+
+    fn error_format(&self) -> ErrorFormat {
+        match self {
+            ParseResult::Get { request, .. } => request.error_format,
+            ParseResult::Post { request, .. } => request.error_format,
+            ParseResult::Other { error_format, .. }
+            | ParseResult::Invalid { error_format, .. } => *error_format,
+        }
+    }
+
+    /// Request path, where the request got far enough to have one.
+    fn path(&self) -> Option<&str> {
+        match self {
+            ParseResult::Get { request, .. } => Some(&request.path),
+            ParseResult::Post { request, .. } => Some(&request.path),
+            ParseResult::Other { path, .. } => Some(path),
+            ParseResult::Invalid { .. } => None,
+        }
+    }
+}
+
+impl Exchange<'_> {
+    /// Answers with `resp`, the connection kept open if the request allows.
+    fn reply(&self, resp: http::Response<Vec<u8>>) -> Option<Reply> {
+        Some(Reply { resp, close: self.close, file_body: None, checksum: None })
+    }
+
+    /// Reader for the request's body.
+    fn body(&mut self) -> BodyReader<'_> {
+        self.buf.advance(std::mem::take(&mut self.body_offset));
+        BodyReader::new(self.stream, self.buf, self.body_len, self.config)
+    }
+
+    /// Reader for the body of an upload, logging its start with
+    /// `--dump-bodies`.
+    fn upload_body(&mut self) -> BodyReader<'_> {
+        let dump_len = if self.config.dump_bodies { DUMP_BODY_LEN } else { 0 };
+        self.body().dump_prefix(dump_len)
+    }
+
+    /// Reads past the request's body, which is of no use. `None` when the
+    /// read failed, and the connection with it.
+    async fn drain(&mut self) -> Option<()> {
+        match self.body().drain().await {
+            Ok(()) => Some(()),
+            Err(err) => self.body_error(err).await,
+        }
+    }
+
+    /// Answers a request with `status` before or while its body is read.
+    /// The rest of the body is left unread, so the connection is closed
+    /// after.
+    async fn refuse<T>(&mut self, status: http::StatusCode) -> Option<T> {
+        let mut resp = BasicHttpServer::response_error(status, self.error_format);
+        *resp.version_mut() = self.version;
+        resp.headers_mut().insert(http::header::CONNECTION, http::HeaderValue::from_static("close"));
+        let resp = BasicHttpServer::serialize_response(resp, self.config.title_case_headers);
+        if let Err(err) = self.stream.write_all(&resp).await {
+            BasicHttpServer::log_conn_error("response write", err);
+            return None;
+        }
+        self.linger().await;
+        None
+    }
+
+    /// Answers a request that ran out of time while its body was being
+    /// read.
+    async fn timed_out<T>(&mut self) -> Option<T> {
+        info!("request timed out");
+        self.refuse(http::StatusCode::REQUEST_TIMEOUT).await
+    }
+
+    /// Answers a request whose body read failed partway, or just logs the
+    /// failure if the connection itself is gone.
+    async fn body_error<T>(&mut self, err: ServerError) -> Option<T> {
+        match BasicHttpServer::body_error_status(&err) {
+            Some(status) => {
+                info!("request body refused: {err}");
+                self.refuse(status).await
+            }
+            None => {
+                BasicHttpServer::log_conn_error("request body read", err);
+                None
+            }
+        }
+    }
+
+    /// Stops writing to the connection and discards what the client still
+    /// sends, for up to [`LINGER_TIMEOUT`], so that closing it doesn't
+    /// reset it.
+    async fn linger(&mut self) {
+        if self.stream.shutdown().await.is_err() {
+            return;
+        }
+        let mut discard = [0u8; 4096];
+        let _ = tokio::time::timeout(LINGER_TIMEOUT, async {
+            while let Ok(1..) = self.stream.read(&mut discard).await {}
+        }).await;
     }
 }
 
 impl BasicHttpServer {
-    pub async fn new(addr: &str, dir: &str) -> Result<BasicHttpServer> {
-        let listener = TcpListener::bind(addr).await?;
-        let dir = dir.to_owned();
+    pub async fn new(addr: &str, mut config: ServerConfig) -> Result<BasicHttpServer> {
+        config.dir = Self::canonical_path(&config.dir).await?;
+        for mount in &mut config.mounts {
+            mount.root = Self::canonical_path(&mount.root).await?;
+        }
+        for vhost in &mut config.vhosts {
+            vhost.dir = Self::canonical_path(&vhost.dir).await?;
+        }
+        if let Some(file) = &mut config.single_file {
+            *file = Self::canonical_path(file).await?;
+            if !tokio::fs::metadata(&file).await?.is_file() {
+                return Err(ServerError::Path {
+                    path: file.clone(),
+                    reason: "not a regular file".to_string(),
+                });
+            }
+        }
+        let listener = Self::bind(addr, &config).await.map_err(|source| ServerError::Bind {
+            addr: addr.to_string(),
+            source,
+        })?;
 
-        info!("started server on {addr} serving files from {dir}");
+        info!("started server on {}", listener.local_addr()?);
+        info!("serving files from {}", config.dir);
+        info!("enabled features: {}", config.enabled_features().join(", "));
         Ok(BasicHttpServer {
             listener,
-            dir,
+            config,
+            routes: vec![],
+            post_handlers: vec![],
+            upgrade_handlers: vec![],
+            files: Arc::new(Disk),
         })
     }
 
+    /// Listens on the first address `addr` resolves to.
+    async fn bind(addr: &str, config: &ServerConfig) -> std::io::Result<TcpListener> {
+        let addr = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                                               "address resolved to nothing"))?;
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(config.reuse_address)?;
+        socket.bind(addr)?;
+        socket.listen(config.backlog)
+    }
+
+    /// Serves `/files` and mounts from `source` instead of the disk.
+    pub fn file_source(&mut self, source: impl FileSource + 'static) {
+        self.files = Arc::new(source);
+    }
+
+    /// Routes `method` requests under `prefix` to `handler`, ahead of the
+    /// built-in endpoints and POST handlers. The longest matching prefix
+    /// wins, and HEAD requests only go to routes for HEAD. Routes apply to
+    /// HTTP/2 requests as well.
+    pub fn route<F, Fut>(&mut self, method: &str, prefix: &str, handler: F)
+    where F: Fn(http::Request<Body>) -> Fut + Send + Sync + 'static,
+          Fut: Future<Output = http::Response<Vec<u8>>> + Send + 'static {
+        let handler: RouteHandler = Arc::new(move |request| Box::pin(handler(request)));
+        self.routes.push(Route {
+            method: method.to_ascii_uppercase(),
+            prefix: prefix.trim_end_matches('/').to_string(),
+            handler,
+        });
+    }
+
+    /// Routes POST requests under `prefix` to `handler` rather than writing
+    /// their bodies to a file. The longest matching prefix wins.
+    pub fn post_handler<F, Fut>(&mut self, prefix: &str, handler: F)
+    where F: Fn(String, Body) -> Fut + Send + Sync + 'static,
+          Fut: Future<Output = http::Response<Vec<u8>>> + Send + 'static {
+        let handler: PostHandler = Arc::new(move |path, body| Box::pin(handler(path, body)));
+        self.post_handlers.push((prefix.trim_end_matches('/').to_string(), handler));
+    }
+
+    /// Hands HTTP/1.1 GET requests asking to switch to `protocol` over to
+    /// `handler`, which gets the connection after the `101 Switching
+    /// Protocols` response and keeps it until it returns.
+    pub fn upgrade_handler<F, Fut>(&mut self, protocol: &str, handler: F)
+    where F: Fn(String, Upgraded) -> Fut + Send + Sync + 'static,
+          Fut: Future<Output = ()> + Send + 'static {
+        let handler: UpgradeHandler = Arc::new(move |path, io| Box::pin(handler(path, io)));
+        self.upgrade_handlers.push((protocol.to_string(), handler));
+    }
+
+    async fn canonical_path(path: &str) -> Result<String> {
+        let invalid = |reason: String| ServerError::Path { path: path.to_string(), reason };
+        tokio::fs::canonicalize(path)
+            .await
+            .map_err(|e| invalid(e.to_string()))?
+            .into_os_string()
+            .into_string()
+            .map_err(|path| invalid(format!("{path:?} is not valid UTF-8")))
+    }
+
+    /// Configuration with the served paths resolved.
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
     pub async fn run(&self) -> Result<()> {
+        let open_files = self.config.max_open_files.map(|max| Arc::new(Semaphore::new(max)));
+        let files = match &open_files {
+            Some(permits) => Arc::new(Limited::new(self.files.clone(), permits.clone())),
+            None => self.files.clone(),
+        };
+        let state = Arc::new(SharedState {
+            config: self.config.clone(),
+            routes: self.routes.clone(),
+            post_handlers: self.post_handlers.clone(),
+            upgrade_handlers: self.upgrade_handlers.clone(),
+            files,
+            open_files,
+            requests: AtomicU64::new(0),
+            metrics: Arc::new(Metrics::default()),
+            shutdown: watch::channel(false).0,
+            write_locks: PathLocks::default(),
+        });
+        if state.config.metrics {
+            tokio::task::spawn(Metrics::sample(Arc::downgrade(&state.metrics)));
+        }
+        let mut shutdown = state.shutdown.subscribe();
+        // Every connection task holds a sender, so the receiver sees the
+        // channel closed once all of them are done.
+        let (conn_tx, mut conn_rx) = mpsc::channel::<()>(1);
+        let mut backoff = ACCEPT_BACKOFF_MIN;
         loop {
-            let (stream, _) = self.listener.accept().await?;
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                _ = shutdown.wait_for(|&down| down) => break,
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => {
+                    backoff = ACCEPT_BACKOFF_MIN;
+                    accepted
+                }
+                Err(err) if Self::accept_error_is_fatal(&err) => return Err(err.into()),
+                Err(err) if Self::accept_error_is_transient(&err) => {
+                    info!("accept error: {err}");
+                    continue;
+                }
+                Err(err) => {
+                    // Most likely out of file descriptors, which only frees
+                    // up as connections are closed.
+                    error!("accept error, retrying in {backoff:?}: {err}");
+                    tokio::time::sleep(backoff).await;
+                    backoff = std::cmp::min(backoff * 2, ACCEPT_BACKOFF_MAX);
+                    continue;
+                }
+            };
+            if let Err(err) = Self::configure_socket(&stream, &state.config) {
+                warn!("failed to set socket options for {peer}: {err}");
+            }
+
+            let (state, conn) = (state.clone(), conn_tx.clone());
+            tokio::task::spawn(async move {
+                Self::handle_request(stream, peer, state).await;
+                drop(conn);
+            });
+        }
+
+        info!("waiting for open connections to finish");
+        drop(conn_tx);
+        conn_rx.recv().await;
+        Ok(())
+    }
+
+    /// Logs an I/O error on a connection, at debug level when it's only the
+    /// client hanging up.
+    fn log_conn_error(what: &str, err: impl Into<ServerError>) {
+        let err = err.into();
+        if err.is_disconnect() {
+            debug!("client disconnected during {what}: {err}");
+        } else {
+            error!("{what} error: {err:?}");
+        }
+    }
+
+    /// Whether an accept error means the listening socket itself is broken,
+    /// rather than a connection or the process running short of resources.
+    fn accept_error_is_fatal(err: &std::io::Error) -> bool {
+        matches!(err.kind(),
+                 std::io::ErrorKind::InvalidInput
+                 | std::io::ErrorKind::NotConnected
+                 | std::io::ErrorKind::Unsupported)
+    }
+
+    /// Errors concerning only the connection being accepted, after which
+    /// the next one can be accepted right away.
+    fn accept_error_is_transient(err: &std::io::Error) -> bool {
+        matches!(err.kind(),
+                 std::io::ErrorKind::ConnectionAborted
+                 | std::io::ErrorKind::ConnectionReset
+                 | std::io::ErrorKind::Interrupted)
+    }
 
-            tokio::task::spawn(Self::handle_request(stream, self.dir.clone()));
+    fn configure_socket(stream: &TcpStream, config: &ServerConfig) -> std::io::Result<()> {
+        stream.set_nodelay(config.tcp_nodelay)?;
+        if let Some(idle) = config.tcp_keepalive {
+            let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+            socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
         }
+        Ok(())
     }
 
-    #[tracing::instrument]
-    async fn handle_request(mut stream: TcpStream, dir: String)
+    #[tracing::instrument(skip(state), fields(client))]
+    async fn handle_request(mut stream: TcpStream, peer: SocketAddr, state: Arc<SharedState>)
     {
+        let config = &state.config;
         info!("starting request handler");
 
+        // Bytes past the end of the current request belong to the next
+        // pipelined one, so the buffer lives across loop iterations.
+        let mut buf: BytesMut = Default::default();
+        let mut at_start = true;
+        let mut throttle = config.max_bandwidth.map(Throttle::new);
+        let mut served: u64 = 0;
         loop {
-            let mut buf: BytesMut = Default::default();
+            // The first request has to arrive in full within the header
+            // timeout of the connection being accepted, later ones within
+            // the timeout of their first byte, so idle keep-alive
+            // connections aren't cut off.
+            let mut header_deadline = at_start.then(|| Instant::now() + config.header_timeout);
             let parse_res = loop {
-                match stream.read_buf(&mut buf).await {
+                if config.http2 && at_start && Self::is_h2_preface_prefix(&buf) {
+                    if buf.len() >= H2_PREFACE.len() {
+                        let io = Rewound::new(buf.split().freeze(), stream);
+                        if let Err(err) = Self::serve_h2(io, peer, state.clone()).await {
+                            error!("HTTP/2 connection error: {err:?}");
+                        }
+                        return;
+                    }
+                } else if !buf.is_empty() {
+                    match Self::parse_request(&buf, config.max_header_size) {
+                        Ok(Some(parse_res)) => break parse_res,
+                        Ok(None) => (),
+                        // Malformed header lines, lengths and the like.
+                        Err(err) => {
+                            info!("malformed request: {err}");
+                            break ParseResult::Invalid {
+                                status: http::StatusCode::BAD_REQUEST,
+                                error_format: ErrorFormat::Text,
+                            };
+                        }
+                    }
+                }
+
+                if header_deadline.is_none() && !buf.is_empty() {
+                    header_deadline = Some(Instant::now() + config.header_timeout);
+                }
+                let read = match header_deadline {
+                    Some(deadline) => {
+                        match tokio::time::timeout_at(deadline, stream.read_buf(&mut buf)).await {
+                            Ok(read) => read,
+                            Err(_) => {
+                                info!("request headers not received in time");
+                                let resp = Self::response408();
+                                if let Err(err) =
+                                    stream
+                                    .write_all(Self::serialize_response(resp, config.title_case_headers).as_slice())
+                                    .await {
+                                        Self::log_conn_error("response write", err);
+                                    }
+                                return;
+                            }
+                        }
+                    }
+                    // Idle between requests, which is as good a time as any
+                    // to go when the server is shutting down.
+                    None => {
+                        let mut shutdown = state.shutdown.subscribe();
+                        tokio::select! {
+                            read = stream.read_buf(&mut buf) => read,
+                            _ = shutdown.wait_for(|&down| down) => {
+                                info!("closing idle connection for shutdown");
+                                return;
+                            }
+                        }
+                    }
+                };
+
+                match read {
                     Ok(0) => {
                         info!("connection closed");
                         return;
@@ -66,72 +886,198 @@ impl BasicHttpServer {
                         info!("read {n} more bytes");
                     }
                     Err(err) => {
-                        error!("read error: {err:?}");
+                        Self::log_conn_error("read", err);
                         return;
                     }
                 }
+            };
 
-                match Self::parse_request(&buf) {
-                    Ok(Some(parse_res)) => break parse_res,
-                    Ok(None) => (),
-                    Err(err) => {
-                        error!("read error: {err:?}");
-                        return;
+            at_start = false;
+            served += 1;
+            state.count_request();
+            let started = Instant::now();
+
+            // Reading an absurdly long body isn't even started, the client
+            // is refused and the connection closed.
+            let parse_res = match parse_res.body_len() {
+                Some(BodyLength::Fixed(len)) if len as u64 > config.max_body_size => {
+                    info!("request body of {len} bytes over {}", config.max_body_size);
+                    ParseResult::Invalid {
+                        status: http::StatusCode::PAYLOAD_TOO_LARGE,
+                        error_format: parse_res.error_format(),
                     }
                 }
+                _ => parse_res,
             };
 
-            let (resp, close_con) = match parse_res {
-                ParseResult::Get { close, path, ua, encoding } => {
-                    let resp = if path == "/" {
-                        Self::response200pt(vec![], encoding)
-                    } else if path.to_ascii_lowercase().starts_with("/echo") {
-                        let body = path[6..].as_bytes().to_vec();
-                        Self::response200pt(body, encoding)
-                    } else if path.to_ascii_lowercase() == "/user-agent" {
-                        let body = ua.unwrap_or("".to_string()).as_bytes().to_vec();
-                        Self::response200pt(body, encoding)
-                    } else if path.to_ascii_lowercase().starts_with("/files") {
-                        let contents = Self::read_file(&path[6..], &dir).await;
-                        match contents {
-                            Ok(c) => {
-                                Self::response200bin(c)
-                            }
-                            Err(e) => {
-                                error!("File read error {e}");
-                                Self::response404()
+            // Uploads make no sense when serving a single file, or files
+            // that can't be written, and the method rules may rule out
+            // more methods still. Requests that must be made with another
+            // protocol are refused the same way, whatever their method.
+            let not_allowed = match &parse_res {
+                _ if parse_res.path().is_some_and(|path| config.upgrade_required(path).is_some()) => true,
+                ParseResult::Post { .. } if config.single_file.is_some() || state.files.read_only() => true,
+                ParseResult::Get { .. } | ParseResult::Post { .. } => {
+                    parse_res.path().zip(parse_res.method())
+                        .is_some_and(|(path, method)| !config.method_allowed(path, method))
+                }
+                _ => false,
+            };
+            let route = parse_res.path().zip(parse_res.method())
+                .filter(|_| !not_allowed)
+                .and_then(|(path, method)| state.route(method, path))
+                .cloned();
+            let routed = route.is_some();
+            let parse_res = if not_allowed { parse_res.into_not_allowed() } else { parse_res };
+
+            let version = parse_res.version();
+            let method = parse_res.method().unwrap_or("-").to_string();
+            let path = parse_res.path().map(str::to_string);
+            let client = Self::client_ip(peer.ip(), parse_res.forwarded_for(), config);
+            Span::current().record("client", tracing::field::display(client));
+
+            let mut ex = Exchange {
+                stream: &mut stream,
+                buf: &mut buf,
+                config,
+                version,
+                close: parse_res.close(),
+                error_format: parse_res.error_format(),
+                body_offset: parse_res.body_offset(),
+                body_len: parse_res.body_len().unwrap_or(BodyLength::Fixed(0)),
+            };
+            let reply = match (parse_res, route) {
+                (_, Some(handler)) => Self::serve_route(&mut ex, handler).await,
+                (ParseResult::Get { head, upgrade, request, .. }, None) => {
+                    if ex.drain().await.is_none() {
+                        return;
+                    }
+
+                    let upgrade = upgrade.filter(|_| version == http::Version::HTTP_11);
+                    if let Some((protocol, handler)) =
+                        upgrade.as_ref().and_then(|u| state.upgrade_handler(&u.protocol)) {
+                        let resp = Self::response101(protocol);
+                        if let Err(err) =
+                            stream
+                            .write_all(Self::serialize_response(resp, config.title_case_headers).as_slice())
+                            .await {
+                                Self::log_conn_error("response write", err);
+                                return;
                             }
-                        }
-                    } else {
-                        Self::response404()
-                    };
+                        info!("switching to {protocol}");
+                        let io = Upgraded::new(Rewound::new(buf.split().freeze(), stream));
+                        handler(request.path, io).await;
+                        return;
+                    }
 
-                    (resp, close)
+                    let websocket = upgrade
+                        .filter(|u| u.protocol.eq_ignore_ascii_case("websocket")
+                                && request.path.eq_ignore_ascii_case("/ws"));
+                    match websocket.map(|websocket| websocket.websocket_key) {
+                        Some(Some(key)) => {
+                            // Frames the client sent right after the
+                            // handshake may already be buffered.
+                            let io = Rewound::new(buf.split().freeze(), stream);
+                            Self::serve_websocket(io, &key, config.title_case_headers).await;
+                            return;
+                        }
+                        Some(None) => {
+                            info!("WebSocket upgrade without a key");
+                            ex.reply(Self::response_error(http::StatusCode::BAD_REQUEST, request.error_format))
+                        }
+                        None => Self::serve_get(&mut ex, &state, peer.ip(), client, request, head).await,
+                    }
                 },
-                ParseResult::Post { close, path, body_offset, body_len } => {
-                    let content_prefix = &buf[body_offset..];
-                    match Self::write_file(&mut stream,
-                                           &path[6..],
-                                           &dir,
-                                           content_prefix,
-                                           body_len).await {
-                        Ok(()) => {
-                            (Self::response201(), close)
+                (ParseResult::Post { request, .. }, None) => Self::serve_post(&mut ex, &state, request).await,
+                (ParseResult::Other { method, path, preflight, error_format, .. }, None) => {
+                    if ex.drain().await.is_none() {
+                        return;
+                    }
+
+                    match (config.upgrade_required(&path), preflight.filter(|_| config.cors)) {
+                        (Some(protocol), _) => {
+                            info!("{path} requires {protocol}");
+                            ex.reply(Self::response426(protocol, error_format))
                         }
-                        Err(e) => {
-                            error!("File read error {e}");
-                            return;
+                        (None, Some(preflight)) => ex.reply(Self::response_preflight(&state, &path, preflight)),
+                        (None, None) => {
+                            info!("method {method} not allowed");
+                            ex.reply(Self::response405(&state, &path, error_format))
                         }
                     }
                 }
+                (ParseResult::Invalid { status, .. }, None) => {
+                    info!("refusing invalid request with {status}");
+                    ex.refuse(status).await
+                }
+            };
+            let Some(Reply { mut resp, close: mut close_con, file_body, checksum }) = reply else {
+                return;
             };
+            *resp.version_mut() = version;
+            if state.shutting_down() && !close_con {
+                resp.headers_mut().insert(http::header::CONNECTION,
+                                          http::HeaderValue::from_static("close"));
+                close_con = true;
+            }
+            if version == http::Version::HTTP_10 && !close_con {
+                resp.headers_mut().insert(http::header::CONNECTION,
+                                          http::HeaderValue::from_static("keep-alive"));
+            }
+            if let Some(path) = &path {
+                Self::add_route_headers(config, path, &mut resp);
+            }
+            // Responses from route handlers are left as they made them.
+            let plain_404 = resp.status() == http::StatusCode::NOT_FOUND
+                && resp.headers().get(http::header::CONTENT_TYPE).is_some_and(|t| t == "text/plain");
+            if config.verbose_404 && plain_404 && !routed && method != "HEAD" {
+                Self::add_404_detail(&mut resp, &method, path.as_deref().unwrap_or_default());
+            }
+            if config.response_time_header {
+                let millis = started.elapsed().as_secs_f64() * 1000.0;
+                resp.headers_mut().insert("x-response-time",
+                                          http::HeaderValue::from_str(&format!("{millis:.3}ms")).unwrap());
+            }
+            if config.debug_headers {
+                resp.headers_mut().insert("x-connection-requests", http::HeaderValue::from(served));
+            }
+
+            // A connection that can't be written to is of no further use.
+            let status = resp.status();
+            let serialized = Self::serialize_response(resp, config.title_case_headers);
+            if let Err(err) = throttle::write_all(&mut stream, &serialized, throttle.as_mut()).await {
+                Self::log_conn_error("response write", err);
+                return;
+            }
+            state.metrics.add_bytes_sent(serialized.len() as u64);
 
-            if let Err(err) =
-                stream
-                .write_all(Self::serialize_response(resp).as_slice())
-                .await {
-                    error!("response write error: {err:?}");
+            let written = match file_body {
+                Some(FileBody::Gzip(file)) => {
+                    Self::write_gzip_chunked(&mut stream, file, config.io_buffer_size, &mut throttle, checksum)
+                        .await
+                }
+                Some(FileBody::Mapped(map)) => {
+                    throttle::write_all(&mut stream, &map, throttle.as_mut())
+                        .await
+                        .map(|()| map.len() as u64)
+                        .map_err(Into::into)
+                }
+                None => Ok(0),
+            };
+            match written {
+                Ok(written) => state.metrics.add_bytes_sent(written),
+                // The headers are already out, so the only way to signal
+                // the failure is to cut the body short.
+                Err(err) => {
+                    Self::log_conn_error("response body write", err);
+                    return;
                 }
+            }
+            info!(target: ACCESS_LOG_TARGET,
+                  "{method} {} {} {:?}",
+                  path.as_deref().unwrap_or("-"),
+                  status.as_u16(),
+                  started.elapsed());
 
             if close_con {
                 return;
@@ -139,98 +1085,1458 @@ impl BasicHttpServer {
         }
     }
 
-    fn parse_request(buf: &[u8]) -> Result<Option<ParseResult>>
-    {
-        let mut headers = [httparse::EMPTY_HEADER; 16];
-        let mut req = httparse::Request::new(&mut headers);
-        let body_offset = match req.parse(buf)? {
-            httparse::Status::Complete(offset) => {
-                info!("parsed request: {req:?}");
-                offset
-            }
-            httparse::Status::Partial => {
-                info!("partial request parse result");
-                return Ok(None)
+    /// Hands a request to the route handler registered for it, the body
+    /// fed to the handler as it's read.
+    async fn serve_route(ex: &mut Exchange<'_>, handler: RouteHandler) -> Option<Reply> {
+        let request = match Self::route_request(&ex.buf[..ex.body_offset]) {
+            Ok(request) => request,
+            Err(e) => {
+                info!("request not routable: {e}");
+                return ex.refuse(http::StatusCode::BAD_REQUEST).await;
             }
         };
+        let timeout = ex.config.route_timeout(request.uri().path());
+        let (tx, body) = Body::channel();
+        let reader = ex.body();
+        let handled = Self::within(timeout, async {
+            tokio::join!(handler(request.map(|()| body)), Body::pump(reader, tx))
+        });
+        let Some((mut resp, pumped)) = handled.await else {
+            return ex.timed_out().await;
+        };
+        if let Err(e) = pumped {
+            return ex.body_error(e).await;
+        }
 
-        match req.method {
-            Some("GET") => {
-                let path = req.path
-                    .ok_or_eyre("missing request method")?
-                    .to_string();
-                let mut close = false;
-                let mut ua = None;
-                let mut encoding = None;
-                for header in headers {
-                    if header.name.eq_ignore_ascii_case("connection") {
-                        close = std::str::from_utf8(header.value)?
-                            .eq_ignore_ascii_case("close");
+        Self::strip_hop_by_hop(resp.headers_mut());
+        ex.reply(Self::with_content_length(resp))
+    }
+
+    /// Answers a GET or HEAD request that isn't an upgrade, its body
+    /// already read. Early hints go out first where configured.
+    async fn serve_get(ex: &mut Exchange<'_>,
+                       state: &SharedState,
+                       peer: IpAddr,
+                       client: IpAddr,
+                       mut request: GetRequest,
+                       head: bool) -> Option<Reply> {
+        let config = ex.config;
+        // Compressed files are streamed with chunked framing, which
+        // HTTP/1.0 doesn't have.
+        if ex.version == http::Version::HTTP_10 {
+            request.encoding = None;
+            request.te_gzip = false;
+            request.te_trailers = false;
+        }
+        // Interim responses are an HTTP/1.1 addition too.
+        let hints = config.early_hints(&request.path);
+        if !hints.is_empty() && ex.version == http::Version::HTTP_11 {
+            let hints = Self::serialize_response(Self::response103(&hints), config.title_case_headers);
+            if let Err(err) = ex.stream.write_all(&hints).await {
+                Self::log_conn_error("response write", err);
+                return None;
+            }
+            state.metrics.add_bytes_sent(hints.len() as u64);
+        }
+        let trailers = request.te_trailers;
+        let timeout = config.route_timeout(&request.path);
+        let response = Self::get_response(state, peer, client, request);
+        let Some((mut resp, file_body)) = Self::within(timeout, response).await else {
+            return ex.timed_out().await;
+        };
+        // HEAD gets the headers a GET would, Content-Length of a
+        // compressed body included, and nothing more.
+        let streamed = resp.headers().contains_key(http::header::TRANSFER_ENCODING);
+        let checksum = (trailers && streamed).then(|| {
+            resp.headers_mut().insert(http::header::TRAILER, http::HeaderValue::from_static("Content-MD5"));
+            Checksum {
+                md5: Md5::new(),
+                encoded: resp.headers().contains_key(http::header::CONTENT_ENCODING),
+            }
+        });
+        if head {
+            resp.body_mut().clear();
+        }
+
+        Some(Reply {
+            resp,
+            close: ex.close,
+            file_body: file_body.filter(|_| !head),
+            checksum,
+        })
+    }
+
+    /// Sends a POST or PUT body where it belongs: to a POST handler, back
+    /// to the client for `/echo`, or into files for a form or an upload.
+    async fn serve_post(ex: &mut Exchange<'_>, state: &SharedState, request: PostRequest) -> Option<Reply> {
+        let handler = (!request.put).then(|| state.post_handler(&request.path)).flatten();
+        if let Some(handler) = handler {
+            Self::serve_post_handler(ex, handler, request.path).await
+        } else if !request.put && strip_path_prefix(&request.path, "/echo").is_some() {
+            Self::serve_echo(ex, &request.path, request.content_type).await
+        } else if let Some(boundary) =
+            request.content_type.as_deref().filter(|_| !request.put).and_then(multipart::boundary) {
+            Self::serve_form(ex, state, &request, &boundary).await
+        } else {
+            Self::serve_upload(ex, state, request).await
+        }
+    }
+
+    /// Hands a POST body to the handler registered for its path as it's
+    /// read.
+    async fn serve_post_handler(ex: &mut Exchange<'_>, handler: &PostHandler, path: String) -> Option<Reply> {
+        // Requests still reading their body when they run out of time
+        // leave the rest of it unread.
+        let timeout = ex.config.route_timeout(&path);
+        let (tx, body) = Body::channel();
+        let reader = ex.upload_body();
+        let handled = Self::within(timeout, async {
+            tokio::join!(handler(path, body), Body::pump(reader, tx))
+        });
+        let Some((resp, pumped)) = handled.await else {
+            return ex.timed_out().await;
+        };
+        if let Err(e) = pumped {
+            return ex.body_error(e).await;
+        }
+
+        ex.reply(resp)
+    }
+
+    /// Sends a POST body back as the response, up to the configured size.
+    async fn serve_echo(ex: &mut Exchange<'_>, path: &str, content_type: Option<String>) -> Option<Reply> {
+        let config = ex.config;
+        let timeout = config.route_timeout(path);
+        let body_len = ex.body_len;
+        let reader = ex.upload_body();
+        let body = Self::read_echo_body(reader, body_len, config.max_request_body_buffer);
+        let Some(body) = Self::within(timeout, body).await else {
+            return ex.timed_out().await;
+        };
+        match body {
+            Ok(Some(body)) => {
+                let cont_type = content_type.unwrap_or("application/octet-stream".to_string());
+                ex.reply(Self::response200(body, cont_type, None))
+            }
+            Ok(None) => {
+                info!("echo body over {} bytes", config.max_request_body_buffer);
+                ex.refuse(http::StatusCode::PAYLOAD_TOO_LARGE).await
+            }
+            Err(e) => ex.body_error(e).await,
+        }
+    }
+
+    /// Writes the files in a form posted to a directory, answering with
+    /// their names.
+    async fn serve_form(ex: &mut Exchange<'_>,
+                        state: &SharedState,
+                        request: &PostRequest,
+                        boundary: &str) -> Option<Reply> {
+        let config = ex.config;
+        // Files in the form go in the directory posted to.
+        let (dir, dir_path) = match Self::upload_path(config, &request.path, request.host.as_deref()).await {
+            Ok(upload_path) => upload_path,
+            Err(status) => return ex.refuse(status).await,
+        };
+        let is_dir = tokio::fs::metadata(resolve_path(dir, &dir_path))
+            .await
+            .is_ok_and(|metadata| metadata.is_dir());
+        if !is_dir && !config.create_parents {
+            info!("form posted to {dir_path}, which isn't a directory");
+            return ex.refuse(http::StatusCode::NOT_FOUND).await;
+        }
+        let timeout = config.route_timeout(&request.path);
+        let reader = ex.upload_body();
+        let _permit = state.open_file_permit().await;
+        let form = Multipart::new(reader, boundary);
+        let written = Self::write_multipart(form, dir, &dir_path, config);
+        let Some(written) = Self::within(timeout, written).await else {
+            return ex.timed_out().await;
+        };
+        match written {
+            Ok(names) => {
+                let body = json::object(&[
+                    ("files", json::array(names.iter().map(|n| json::string(n)))),
+                ]);
+                let mut resp = Self::response200json(body.into_bytes());
+                *resp.status_mut() = http::StatusCode::CREATED;
+                ex.reply(resp)
+            }
+            Err(e) if e.is_disconnect() => {
+                Self::log_conn_error("multipart upload", e);
+                None
+            }
+            Err(e) => {
+                let status = Self::body_error_status(&e).unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR);
+                error!("multipart upload error: {e:?}");
+                ex.refuse(status).await
+            }
+        }
+    }
+
+    /// Writes an upload's body to its file, or a part of it for a PUT with
+    /// a content range, once the request's preconditions hold.
+    async fn serve_upload(ex: &mut Exchange<'_>, state: &SharedState, request: PostRequest) -> Option<Reply> {
+        let config = ex.config;
+        let PostRequest { put, path, host, range, conditions, content_type, error_format } = request;
+        let (dir, file_path) = match Self::upload_path(config, &path, host.as_deref()).await {
+            Ok(upload_path) => upload_path,
+            Err(status) => return ex.refuse(status).await,
+        };
+
+        let _write_guard = match conditions.is_empty() {
+            true => None,
+            false => Some(state.write_locks.lock(resolve_path(dir, &file_path)).await),
+        };
+        let info = state.files.info(dir, &file_path).await;
+        if !Self::write_preconditions_hold(&conditions, info.as_ref()) {
+            info!("upload precondition failed for {file_path}");
+            ex.drain().await?;
+            return ex.reply(Self::response_error(http::StatusCode::PRECONDITION_FAILED, error_format));
+        }
+
+        let timeout = config.route_timeout(&path);
+        let reader = ex.upload_body();
+        let _permit = state.open_file_permit().await;
+        let written = Self::write_file(reader, &file_path, dir, range.as_ref(),
+                                       config.create_parents, config.file_mode);
+        let Some(written) = Self::within(timeout, written).await else {
+            return ex.timed_out().await;
+        };
+        // Part of a file leaves its type as it was unless a new one is
+        // given.
+        if written.is_ok() && config.store_content_type && (content_type.is_some() || range.is_none()) {
+            if let Err(e) = Self::store_content_type(dir, &file_path, content_type.as_deref()).await {
+                error!("content type write error: {e}");
+            }
+        }
+        match written {
+            Ok(written) if (put && info.is_some()) || (written == 0 && config.empty_upload_no_content) => {
+                ex.reply(Self::response_status(http::StatusCode::NO_CONTENT))
+            }
+            Ok(_) => ex.reply(Self::response201()),
+            Err(e) if Self::body_error_status(&e).is_some() => ex.body_error(e).await,
+            Err(e) if !state.files.available(dir).await => {
+                warn!("served directory {dir} is unavailable: {e}");
+                ex.refuse(http::StatusCode::SERVICE_UNAVAILABLE).await
+            }
+            Err(e) => {
+                Self::log_conn_error("upload", e);
+                None
+            }
+        }
+    }
+
+    /// Builds the response to a GET request. When the body is to be gzip
+    /// streamed or mapped from a file, that's returned along with a
+    /// response carrying just the headers.
+    async fn get_response(state: &SharedState, peer: IpAddr, client: IpAddr, request: GetRequest)
+                          -> (http::Response<Vec<u8>>, Option<FileBody>) {
+        let config = &state.config;
+        let GetRequest {
+            path, host, query, ua, encoding, precompressed, te_gzip, error_format, conditions, ..
+        } = request;
+        let te_gzip = te_gzip && !config.no_compression;
+        let mut file_body = None;
+        let encoding = match encoding {
+            _ if config.no_compression => None,
+            Some(HttpEncoding::Deflate) if config.deflate_raw => Some(HttpEncoding::DeflateRaw),
+            encoding => encoding,
+        };
+
+        let resp = if let Some(file) = &config.single_file {
+            let _permit = state.open_file_permit().await;
+            match tokio::fs::read(file).await {
+                Ok(contents) => {
+                    let cont_type = mime::from_path(Path::new(file));
+                    let mut resp = Self::response200(contents, cont_type.to_string(), None);
+                    resp.headers_mut().insert(http::header::ACCEPT_RANGES,
+                                              http::HeaderValue::from_static("none"));
+                    resp
+                }
+                Err(e) => {
+                    error!("File read error {e}");
+                    Self::response_error(http::StatusCode::NOT_FOUND, error_format)
+                }
+            }
+        } else if path == "/" {
+            let body = config.root_message.as_deref().unwrap_or_default().as_bytes().to_vec();
+            let encoding = encoding.filter(|_| body.len() >= COMPRESS_MIN_LEN);
+            Self::response200pt(body, encoding)
+        } else if path.to_ascii_lowercase().starts_with("/echo") {
+            let text = path.get(6..).unwrap_or_default();
+            let format = query.as_deref().and_then(|q| uri::query_param(q, "format"));
+            let body = match format.as_deref() {
+                Some("upper") => text.to_uppercase().into_bytes(),
+                _ => text.as_bytes().to_vec(),
+            };
+            // Lets clients try out ranges without any files.
+            let len = body.len() as u64;
+            match conditions.range.as_deref().map(|range| Self::parse_range(range, len)) {
+                _ if format.as_deref() == Some("json") => {
+                    Self::response200json(json::object(&[("echo", json::string(text))]).into_bytes())
+                }
+                Some(RangeRequest::Satisfiable(start, end)) => {
+                    let part = body[start as usize..=end as usize].to_vec();
+                    let mut resp = Self::response206(part, start, end, len);
+                    resp.headers_mut().insert(http::header::CONTENT_TYPE,
+                                              http::HeaderValue::from_static("text/plain"));
+                    resp
+                }
+                Some(RangeRequest::Unsatisfiable) => Self::response416(len, error_format),
+                _ => Self::response200pt(body, encoding),
+            }
+        } else if path.eq_ignore_ascii_case("/user-agent") {
+            let body = ua.unwrap_or("".to_string()).as_bytes().to_vec();
+            let encoding = encoding.filter(|_| body.len() >= COMPRESS_MIN_LEN);
+            Self::response200pt(body, encoding)
+        } else if path.eq_ignore_ascii_case("/version") {
+            Self::response200json(Self::version_json().into_bytes())
+        } else if path.eq_ignore_ascii_case("/metrics") && config.metrics {
+            let requests = state.requests.load(Ordering::Relaxed);
+            let body = state.metrics.render(requests).into_bytes();
+            Self::response200(body, "text/plain; version=0.0.4".to_string(), None)
+        } else if path.eq_ignore_ascii_case("/teapot") && config.teapot {
+            Self::response418()
+        } else if path.eq_ignore_ascii_case("/debug/config")
+            && config.debug
+            // Forwarded addresses are only as good as the proxies giving
+            // them, so the connection itself has to be local too.
+            && peer.is_loopback()
+            && client.is_loopback() {
+            Self::response200json(config.to_json().into_bytes())
+        } else if let Some(secs) = strip_path_prefix(&path, "/delay")
+            .filter(|_| config.test_endpoints) {
+            match secs.trim_start_matches('/').parse().ok().filter(|&secs| secs <= MAX_DELAY_SECS) {
+                Some(secs) => {
+                    tokio::time::sleep(Duration::from_secs(secs)).await;
+                    Self::response200pt(vec![], None)
+                }
+                None => Self::response_error(http::StatusCode::BAD_REQUEST, error_format),
+            }
+        } else if let Some(code) = strip_path_prefix(&path, "/status")
+            .filter(|_| config.test_endpoints) {
+            // An interim status would leave the client waiting for a final
+            // response that never comes.
+            let status = code.trim_start_matches('/')
+                .parse()
+                .ok()
+                .filter(|code| (200..600).contains(code))
+                .and_then(|code| http::StatusCode::from_u16(code).ok());
+            match status {
+                Some(status) => Self::response_status(status),
+                None => Self::response_error(http::StatusCode::BAD_REQUEST, error_format),
+            }
+        } else if let Some((dir, rest)) = config.resolve_mount(&path, host.as_deref()) {
+            let file_path = match Self::file_request_path(rest, config) {
+                Ok(file_path) => {
+                    let file_path =
+                        Self::index_path(&*state.files, dir, file_path, &config.index_names).await;
+                    Self::check_symlinks(dir, &file_path, config)
+                        .await
+                        .map(|()| file_path)
+                }
+                Err(status) => Err(status),
+            };
+            match file_path {
+                Ok(file_path) => {
+                    let codings = FileCodings {
+                        encoding: &encoding,
+                        precompressed: if config.precompressed { &precompressed } else { &[] },
+                        te_gzip,
+                    };
+                    // Read ahead of the file itself, whose body may hold on
+                    // to the last open file permit until it's sent.
+                    let stored_type = match config.store_content_type {
+                        true => Self::stored_content_type(&*state.files, dir, &file_path).await,
+                        false => None,
+                    };
+                    // Templates are filled in in memory, so they're never mapped.
+                    let template = config.templates && file_path.to_ascii_lowercase().ends_with(".html");
+                    let mmap_min_size = config.mmap_min_size.filter(|_| !template);
+                    let (mut resp, file) =
+                        Self::file_response(&*state.files, dir, &file_path, codings, mmap_min_size,
+                                            &conditions, error_format).await;
+                    file_body = file;
+
+                    if template && file_body.is_none() && resp.status() == http::StatusCode::OK {
+                        Self::render_template(&mut resp, &path);
+                    }
+
+                    let download = config.force_download
+                        || query.as_deref()
+                            .and_then(|q| uri::query_param(q, "download"))
+                            .is_some_and(|d| d == "1" || d == "true");
+                    if let Some(cont_type) = stored_type.filter(|_| resp.status().is_success()) {
+                        resp.headers_mut().insert(http::header::CONTENT_TYPE, cont_type);
+                    }
+                    if download && resp.status().is_success() {
+                        let name = file_path.rsplit('/').next().unwrap_or_default();
+                        resp.headers_mut().insert(http::header::CONTENT_DISPOSITION,
+                                                  Self::content_disposition(name));
+                    }
+                    if let Some(max_age) = config.cache_max_age {
+                        if resp.status().is_success()
+                            || resp.status() == http::StatusCode::NOT_MODIFIED {
+                            Self::add_cache_headers(&mut resp, max_age, config.cache_immutable);
+                        }
+                    }
+                    resp
+                }
+                Err(status) => Self::response_error(status, error_format),
+            }
+        } else {
+            Self::response_error(http::StatusCode::NOT_FOUND, error_format)
+        };
+
+        (resp, file_body)
+    }
+
+    /// Runs `fut` to completion, or for `timeout` at most when given,
+    /// returning `None` if it ran out.
+    async fn within<F: Future>(timeout: Option<Duration>, fut: F) -> Option<F::Output> {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, fut).await.ok(),
+            None => Some(fut.await),
+        }
+    }
+
+    /// The status answering a request whose body couldn't be read, if the
+    /// client is still there to be told.
+    fn body_error_status(err: &ServerError) -> Option<http::StatusCode> {
+        match err {
+            ServerError::BodyTooLarge(_) => Some(http::StatusCode::PAYLOAD_TOO_LARGE),
+            ServerError::Request(_) | ServerError::Parse(_) => Some(http::StatusCode::BAD_REQUEST),
+            _ => None,
+        }
+    }
+
+    /// The served directory an upload to `path` goes in, and the path
+    /// below it, or the status refusing the upload.
+    async fn upload_path<'a>(config: &'a ServerConfig, path: &'a str, host: Option<&str>)
+                             -> std::result::Result<(&'a str, String), http::StatusCode> {
+        let (dir, rest) = config.resolve_mount(path, host).ok_or(http::StatusCode::NOT_FOUND)?;
+        let file_path = Self::file_request_path(rest, config)?;
+        Self::check_symlinks(dir, &file_path, config).await?;
+        Ok((dir, file_path))
+    }
+
+    /// Fills in the placeholders of an HTML file. The file's validators
+    /// and ranges no longer describe what's sent, so they're dropped.
+    fn render_template(resp: &mut http::Response<Vec<u8>>, request_path: &str) {
+        let Ok(html) = std::str::from_utf8(resp.body()) else {
+            return;
+        };
+        if !html.contains("{{") {
+            return;
+        }
+        let html = html
+            .replace("{{SERVER_TIME}}", &http_date::format(SystemTime::now()))
+            .replace("{{REQUEST_PATH}}", &Self::escape_html(request_path));
+
+        let headers = resp.headers_mut();
+        headers.remove(http::header::ETAG);
+        headers.remove(http::header::LAST_MODIFIED);
+        headers.insert(http::header::ACCEPT_RANGES, http::HeaderValue::from_static("none"));
+        headers.insert(http::header::CONTENT_LENGTH, http::HeaderValue::from(html.len()));
+        *resp.body_mut() = html.into_bytes();
+    }
+
+    fn escape_html(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                '\'' => escaped.push_str("&#39;"),
+                c => escaped.push(c),
+            }
+        }
+        escaped
+    }
+
+    /// The crate version, and the commit it was built from when
+    /// `GIT_COMMIT` was set for the build.
+    fn version_json() -> String {
+        json::object(&[
+            ("version", json::string(env!("CARGO_PKG_VERSION"))),
+            ("commit", option_env!("GIT_COMMIT").map_or("null".to_string(), json::string)),
+        ])
+    }
+
+    /// Serves a file below `dir`, gzip-streaming it when it's large and the
+    /// client accepts gzip, as a content coding or else through `TE`, and
+    /// mapping it when it's at least `mmap_min_size` bytes. A file to
+    /// stream or write from its map is returned alongside a response
+    /// carrying just the headers.
+    async fn file_response(files: &dyn FileSource,
+                           dir: &str,
+                           file_path: &str,
+                           codings: FileCodings<'_>,
+                           mmap_min_size: Option<u64>,
+                           conditions: &Conditions,
+                           error_format: ErrorFormat)
+                           -> (http::Response<Vec<u8>>, Option<FileBody>) {
+        let Some(info) = files.info(dir, file_path).await else {
+            if !files.available(dir).await {
+                warn!("served directory {dir} is unavailable");
+                return (Self::response_error(http::StatusCode::SERVICE_UNAVAILABLE, error_format), None);
+            }
+            return (Self::response_error(http::StatusCode::NOT_FOUND, error_format), None);
+        };
+        // Ranges refer to the plain file, so stored variants only stand in
+        // for whole-file requests.
+        if conditions.range.is_none() {
+            if let Some(resp) =
+                Self::precompressed_response(files, dir, file_path, codings.precompressed,
+                                             conditions).await {
+                return (resp, None);
+            }
+        }
+        // Ranges refer to the uncompressed file, so they're served as is.
+        let range = conditions.range.as_deref()
+            .map_or(RangeRequest::Ignored, |range| Self::parse_range(range, info.len));
+        let gzip_stream = info.len >= GZIP_STREAM_MIN_LEN && matches!(range, RangeRequest::Ignored);
+        let content_gzip = gzip_stream && matches!(codings.encoding, Some(HttpEncoding::Gzip));
+
+        // The gzip content coding makes for a representation of its own,
+        // which caches and validators mustn't mix up with the plain file.
+        // A transfer coding doesn't.
+        let etag = Self::etag(&info);
+        let etag = if content_gzip { Self::coded_etag(&etag, "gzip") } else { etag };
+        let last_modified = info.modified;
+        if Self::not_modified(conditions, &etag, last_modified) {
+            let mut resp = Self::response304(&etag, last_modified);
+            if content_gzip {
+                resp.headers_mut().insert(http::header::VARY, http::HeaderValue::from_static("Accept-Encoding"));
+            }
+            return (resp, None);
+        }
+
+        match range {
+            RangeRequest::Satisfiable(start, end) => {
+                return match Self::read_file_range(files, file_path, dir, start, end).await {
+                    Ok(c) => {
+                        let resp = Self::response206(c, start, end, info.len);
+                        (Self::with_validators(resp, &etag, last_modified), None)
+                    }
+                    Err(e) => {
+                        error!("File read error {e}");
+                        (Self::response_error(http::StatusCode::NOT_FOUND, error_format), None)
+                    }
+                };
+            }
+            RangeRequest::Unsatisfiable => {
+                return (Self::response416(info.len, error_format), None);
+            }
+            RangeRequest::Ignored => (),
+        }
+
+        if content_gzip || (gzip_stream && codings.te_gzip) {
+            return match files.open(dir, file_path).await {
+                Ok(file) => {
+                    let resp = if content_gzip {
+                        let mut resp = Self::response200gzip_chunked();
+                        resp.headers_mut().insert(http::header::VARY,
+                                                  http::HeaderValue::from_static("Accept-Encoding"));
+                        resp
+                    } else {
+                        Self::response200gzip_transfer()
+                    };
+                    (Self::with_validators(resp, &etag, last_modified), Some(FileBody::Gzip(file)))
+                }
+                Err(e) => {
+                    error!("File read error {e}");
+                    (Self::response_error(http::StatusCode::NOT_FOUND, error_format), None)
+                }
+            };
+        }
+
+        if mmap_min_size.is_some_and(|min| info.len >= min) {
+            match files.map(dir, file_path).await {
+                Some(Ok(map)) => {
+                    let mut resp = Self::response200bin(vec![]);
+                    resp.headers_mut().insert(http::header::CONTENT_LENGTH, http::HeaderValue::from(map.len()));
+                    return (Self::with_validators(resp, &etag, last_modified), Some(FileBody::Mapped(map)));
+                }
+                Some(Err(e)) => warn!("mapping {file_path} failed, reading it instead: {e}"),
+                None => (),
+            }
+        }
+
+        let contents = Self::read_file(files, file_path, dir).await;
+        match contents {
+            Ok(c) => {
+                (Self::with_validators(Self::response200bin(c), &etag, last_modified), None)
+            }
+            Err(e) => {
+                error!("File read error {e}");
+                (Self::response_error(http::StatusCode::NOT_FOUND, error_format), None)
+            }
+        }
+    }
+
+    /// Serves the first of the client's accepted encodings the file is
+    /// stored in, if any.
+    async fn precompressed_response(files: &dyn FileSource,
+                                    dir: &str,
+                                    file_path: &str,
+                                    precompressed: &[Precompressed],
+                                    conditions: &Conditions)
+                                    -> Option<http::Response<Vec<u8>>> {
+        for &stored in precompressed {
+            let path = format!("{file_path}{}", stored.extension());
+            let Some(info) = files.info(dir, &path).await else {
+                continue;
+            };
+            let etag = Self::etag(&info);
+            if Self::not_modified(conditions, &etag, info.modified) {
+                return Some(Self::response304(&etag, info.modified));
+            }
+            match Self::read_file(files, &path, dir).await {
+                Ok(c) => {
+                    let mut resp = Self::with_validators(Self::response200bin(c), &etag, info.modified);
+                    let headers = resp.headers_mut();
+                    headers.insert(http::header::CONTENT_ENCODING,
+                                   http::HeaderValue::from_static(stored.name()));
+                    headers.insert(http::header::VARY,
+                                   http::HeaderValue::from_static("Accept-Encoding"));
+                    return Some(resp);
+                }
+                Err(e) => error!("File read error {e}"),
+            }
+        }
+        None
+    }
+
+    /// Interprets a `Range` header for a file of `len` bytes. Only a single
+    /// `bytes` range is honoured, anything else is ignored. A range covering
+    /// the whole file, like `bytes=0-`, is satisfiable all the same, since
+    /// download managers expect a 206 to any range they ask for. Other
+    /// units, like `items=0-10`, are ignored as well: the client gets the
+    /// whole file rather than a 416 it can't act on.
+    fn parse_range(range: &str, len: u64) -> RangeRequest {
+        let Some((start, end)) = range.trim()
+            .split_once('=')
+            .filter(|(unit, _)| unit.trim().eq_ignore_ascii_case("bytes"))
+            .map(|(_, spec)| spec)
+            .filter(|spec| !spec.contains(','))
+            .and_then(|spec| spec.split_once('-')) else {
+            return RangeRequest::Ignored;
+        };
+        let (start, end) = (start.trim(), end.trim());
+
+        if start.is_empty() {
+            // A suffix range: the last `end` bytes.
+            return match end.parse::<u64>() {
+                Ok(0) => RangeRequest::Unsatisfiable,
+                Ok(_) if len == 0 => RangeRequest::Unsatisfiable,
+                Ok(suffix) => RangeRequest::Satisfiable(len.saturating_sub(suffix), len - 1),
+                Err(_) => RangeRequest::Ignored,
+            };
+        }
+
+        let Ok(start) = start.parse::<u64>() else {
+            return RangeRequest::Ignored;
+        };
+        let end = match end {
+            "" => None,
+            end => match end.parse::<u64>() {
+                Ok(end) if end >= start => Some(end),
+                _ => return RangeRequest::Ignored,
+            },
+        };
+        if start >= len {
+            return RangeRequest::Unsatisfiable;
+        }
+        RangeRequest::Satisfiable(start, end.map_or(len - 1, |end| end.min(len - 1)))
+    }
+
+    /// Strong validator derived from the file's size and modification time.
+    fn etag(info: &FileInfo) -> String {
+        let mtime = info.modified
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        format!("\"{:x}-{:x}\"", info.len, mtime.as_nanos())
+    }
+
+    /// Validator of a content-coded variant of the file `etag` is for.
+    fn coded_etag(etag: &str, coding: &str) -> String {
+        format!("{}-{coding}\"", etag.trim_end_matches('"'))
+    }
+
+    /// Whether a GET can be answered with 304. If-None-Match takes
+    /// precedence: when present, If-Modified-Since is ignored entirely.
+    fn not_modified(conditions: &Conditions,
+                    etag: &str,
+                    last_modified: Option<SystemTime>) -> bool {
+        if let Some(if_none_match) = &conditions.if_none_match {
+            return Self::etag_matches(if_none_match, etag);
+        }
+
+        let since = conditions.if_modified_since.as_deref().and_then(http_date::parse);
+        match (since, last_modified) {
+            // HTTP dates have a resolution of one second.
+            (Some(since), Some(modified)) => {
+                let secs = |t: SystemTime| t.duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                secs(modified) <= secs(since)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether an upload may replace the file described by `info`, `None`
+    /// when there's no such file yet.
+    fn write_preconditions_hold(conditions: &WriteConditions, info: Option<&FileInfo>) -> bool {
+        let etag = info.map(Self::etag);
+        let if_match = conditions.if_match.as_deref()
+            .is_none_or(|list| etag.as_deref().is_some_and(|etag| Self::etag_matches_strong(list, etag)));
+        let if_none_match = conditions.if_none_match.as_deref()
+            .is_none_or(|list| etag.as_deref().is_none_or(|etag| !Self::etag_matches(list, etag)));
+        if_match && if_none_match
+    }
+
+    /// Weak comparison of `etag` against an If-None-Match list.
+    fn etag_matches(list: &str, etag: &str) -> bool {
+        let etag = etag.trim_start_matches("W/");
+        list.split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    }
+
+    /// Strong comparison of `etag` against an If-Match list, where weak
+    /// tags never match.
+    fn etag_matches_strong(list: &str, etag: &str) -> bool {
+        !etag.starts_with("W/")
+            && list.split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag == etag)
+    }
+
+    /// `attachment` disposition for `name`, with an ASCII-only `filename`
+    /// fallback and the exact name in `filename*` when they differ.
+    fn content_disposition(name: &str) -> http::HeaderValue {
+        let fallback: String = name
+            .chars()
+            .map(|c| if (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' { c } else { '_' })
+            .collect();
+        let value = if fallback == name {
+            format!("attachment; filename=\"{name}\"")
+        } else {
+            format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{}",
+                    uri::percent_encode_attr(name))
+        };
+        http::HeaderValue::from_str(&value).unwrap()
+    }
+
+    fn is_h2_preface_prefix(buf: &[u8]) -> bool {
+        let n = std::cmp::min(buf.len(), H2_PREFACE.len());
+        buf[..n] == H2_PREFACE[..n]
+    }
+
+    /// Completes a WebSocket handshake and echoes frames until the
+    /// connection is closed.
+    async fn serve_websocket<T>(mut io: T, key: &str, title_case: bool)
+    where T: AsyncRead + AsyncWrite + Unpin {
+        let mut resp = Self::response101("websocket");
+        resp.headers_mut().insert(http::header::SEC_WEBSOCKET_ACCEPT,
+                                  http::HeaderValue::from_str(&websocket::accept_key(key)).unwrap());
+        if let Err(err) = io.write_all(Self::serialize_response(resp, title_case).as_slice()).await {
+            Self::log_conn_error("response write", err);
+            return;
+        }
+
+        info!("serving WebSocket connection");
+        if let Err(err) = websocket::echo(io).await {
+            Self::log_conn_error("WebSocket", err);
+        }
+    }
+
+    /// Serves an HTTP/2 connection started with prior knowledge, handling
+    /// each stream as its own task.
+    async fn serve_h2<T>(io: T, peer: SocketAddr, state: Arc<SharedState>) -> Result<()>
+    where T: AsyncRead + AsyncWrite + Unpin {
+        let mut conn = h2::server::handshake(io).await?;
+        info!("serving HTTP/2 connection");
+
+        let mut shutdown = state.shutdown.subscribe();
+        let mut closing = false;
+        loop {
+            let request = tokio::select! {
+                request = conn.accept() => request,
+                _ = shutdown.wait_for(|&down| down), if !closing => {
+                    // Lets the streams already open finish, then closes.
+                    conn.graceful_shutdown();
+                    closing = true;
+                    continue;
+                }
+            };
+            let Some(request) = request else { break };
+            let (request, respond) = request?;
+            state.count_request();
+            tokio::task::spawn(Self::respond_h2(request, respond, peer, state.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Requests with a route go to its handler as over HTTP/1. Otherwise
+    /// only GET and HEAD are served over HTTP/2, other methods get a 405.
+    async fn respond_h2(request: http::Request<h2::RecvStream>,
+                        mut respond: h2::server::SendResponse<Bytes>,
+                        peer: SocketAddr,
+                        state: Arc<SharedState>) {
+        let header = |name| request.headers().get(name).and_then(|v| v.to_str().ok());
+        let error_format = header(http::header::ACCEPT)
+            .map_or(ErrorFormat::Text, Self::parse_accept);
+
+        let path = request.uri().path().to_string();
+        let head = request.method() == http::Method::HEAD;
+        let allowed = state.config.method_allowed(&path, request.method().as_str());
+        let route = state.route(request.method().as_str(), &path)
+            .filter(|_| allowed)
+            .cloned();
+        let mut resp = if let Some(handler) = route {
+            let timeout = state.config.route_timeout(&path);
+            match Self::within(timeout, Self::route_h2(handler, request)).await {
+                Some(resp) => resp,
+                None => {
+                    info!("request timed out");
+                    Self::response_error(http::StatusCode::REQUEST_TIMEOUT, error_format)
+                }
+            }
+        } else if (request.method() == http::Method::GET || head) && allowed {
+            let get = GetRequest {
+                path: request.uri().path().to_string(),
+                host: request.uri().host()
+                    .or(header(http::header::HOST))
+                    .map(str::to_string),
+                query: request.uri().query().map(str::to_string),
+                ua: header(http::header::USER_AGENT).map(str::to_string),
+                // Compressed bodies are only streamed with HTTP/1.1 chunked
+                // framing, so files go out uncompressed.
+                encoding: None,
+                precompressed: vec![],
+                // HTTP/2 has no transfer codings.
+                te_gzip: false,
+                te_trailers: false,
+                error_format,
+                conditions: Conditions {
+                    if_none_match: header(http::header::IF_NONE_MATCH).map(str::to_string),
+                    if_modified_since: header(http::header::IF_MODIFIED_SINCE).map(str::to_string),
+                    range: header(http::header::RANGE).map(str::to_string),
+                },
+            };
+            let (mut resp, file_body) = Self::get_response(&state, peer.ip(), peer.ip(), get).await;
+            // Data frames own their bytes, so a mapped file is copied out.
+            if let Some(FileBody::Mapped(map)) = file_body {
+                resp.body_mut().extend_from_slice(&map);
+            }
+            resp
+        } else {
+            Self::response405(&state, &path, error_format)
+        };
+        Self::add_route_headers(&state.config, &path, &mut resp);
+
+        let (parts, body) = resp.into_parts();
+        let end_of_stream = head || body.is_empty();
+        match respond.send_response(http::Response::from_parts(parts, ()), end_of_stream) {
+            Ok(mut send) => {
+                if !end_of_stream {
+                    let len = body.len() as u64;
+                    match send.send_data(body.into(), true) {
+                        Ok(()) => state.metrics.add_bytes_sent(len),
+                        Err(err) => error!("HTTP/2 body write error: {err:?}"),
+                    }
+                }
+            }
+            Err(err) => {
+                error!("HTTP/2 response write error: {err:?}");
+            }
+        }
+    }
+
+    /// Runs a route handler on an HTTP/2 request, with the stream's data
+    /// frames as the body.
+    async fn route_h2(handler: RouteHandler, request: http::Request<h2::RecvStream>)
+                      -> http::Response<Vec<u8>> {
+        let (parts, mut recv) = request.into_parts();
+        let (tx, body) = Body::channel();
+        let pump = async move {
+            while let Some(data) = recv.data().await {
+                // Each chunk handed on makes room for the client to send more.
+                let data = data
+                    .inspect(|chunk| { let _ = recv.flow_control().release_capacity(chunk.len()); })
+                    .map_err(ServerError::from);
+                if tx.send(data).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let mut request = http::Request::from_parts(parts, body);
+        Self::strip_hop_by_hop(request.headers_mut());
+        // HTTP/2 refuses to send connection-specific headers at all.
+        let mut resp = tokio::join!(handler(request), pump).0;
+        Self::strip_hop_by_hop(resp.headers_mut());
+        resp
+    }
+
+    /// Parses the request at the start of `buf`, `None` if its headers are
+    /// still incomplete. Only the request line and headers count towards
+    /// `max_header_size`, whatever follows them in `buf` doesn't.
+    fn parse_request(buf: &[u8], max_header_size: usize) -> Result<Option<ParseResult>>
+    {
+        let too_large = || {
+            info!("request headers larger than {max_header_size} bytes");
+            Ok(Some(ParseResult::Invalid {
+                status: http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                error_format: ErrorFormat::Text,
+            }))
+        };
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut req = httparse::Request::new(&mut headers);
+        let body_offset = match req.parse(buf) {
+            Ok(httparse::Status::Complete(offset)) if offset > max_header_size => return too_large(),
+            Ok(httparse::Status::Complete(offset)) => {
+                info!("parsed request: {req:?}");
+                offset
+            }
+            // Everything buffered is still part of the headers.
+            Ok(httparse::Status::Partial) if buf.len() > max_header_size => return too_large(),
+            Ok(httparse::Status::Partial) => {
+                info!("partial request parse result");
+                return Ok(None)
+            }
+            // Anything but HTTP/1.0 and HTTP/1.1 in the request line.
+            Err(httparse::Error::Version) => {
+                info!("unsupported HTTP version");
+                return Ok(Some(ParseResult::Invalid {
+                    status: http::StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+                    error_format: ErrorFormat::Text,
+                }));
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        // HTTP/1.1 requests must carry exactly one Host header.
+        let hosts = req.headers.iter().filter(|h| h.name.eq_ignore_ascii_case("host")).count();
+        if req.version == Some(1) && hosts != 1 {
+            info!("HTTP/1.1 request with {hosts} Host headers");
+            let error_format = match req.headers.iter().find(|h| h.name.eq_ignore_ascii_case("accept")) {
+                Some(accept) => Self::parse_accept(std::str::from_utf8(accept.value)?),
+                None => ErrorFormat::Text,
+            };
+            return Ok(Some(ParseResult::Invalid {
+                status: http::StatusCode::BAD_REQUEST,
+                error_format,
+            }));
+        }
+
+        let version = match req.version {
+            Some(0) => http::Version::HTTP_10,
+            _ => http::Version::HTTP_11,
+        };
+        let forwarded_for = Self::parse_forwarded_for(req.headers)?;
+        let body_len = Self::parse_body_length(req.headers)?;
+
+        match req.method {
+            Some(method @ ("GET" | "HEAD")) => {
+                let (path, query) = uri::split_query(req.path.ok_or(httparse::Error::Token)?);
+                let (path, query) = (path.to_string(), query.map(str::to_string));
+                let mut close = version == http::Version::HTTP_10;
+                let mut host = None;
+                let mut ua = None;
+                let mut upgrade = None;
+                let mut websocket_key = None;
+                let mut encoding = None;
+                let mut precompressed = vec![];
+                let mut te_gzip = false;
+                let mut te_trailers = false;
+                let mut error_format = ErrorFormat::Text;
+                let mut conditions = Conditions::default();
+                for header in headers {
+                    if header.name.eq_ignore_ascii_case("connection") {
+                        close = Self::connection_closes(std::str::from_utf8(header.value)?, version);
+                    } else if header.name.eq_ignore_ascii_case("host") {
+                        host = Some(std::str::from_utf8(header.value)?.to_owned());
+                    } else if header.name.eq_ignore_ascii_case("if-none-match") {
+                        conditions.if_none_match = Some(std::str::from_utf8(header.value)?.to_owned());
+                    } else if header.name.eq_ignore_ascii_case("if-modified-since") {
+                        conditions.if_modified_since = Some(std::str::from_utf8(header.value)?.to_owned());
+                    } else if header.name.eq_ignore_ascii_case("range") {
+                        conditions.range = Some(std::str::from_utf8(header.value)?.to_owned());
                     } else if header.name.eq_ignore_ascii_case("user-agent") {
                         ua = Some(std::str::from_utf8(header.value)?.to_owned());
+                    } else if header.name.eq_ignore_ascii_case("upgrade") {
+                        upgrade = Some(std::str::from_utf8(header.value)?.trim().to_owned());
+                    } else if header.name.eq_ignore_ascii_case("sec-websocket-key") {
+                        websocket_key = Some(std::str::from_utf8(header.value)?.to_owned());
                     } else if header.name.eq_ignore_ascii_case("accept-encoding") {
-                        encoding = Self::parse_encoding(std::str::from_utf8(header.value)?);
+                        let accept = std::str::from_utf8(header.value)?;
+                        encoding = Self::parse_encoding(accept);
+                        precompressed = Self::parse_precompressed(accept);
+                    } else if header.name.eq_ignore_ascii_case("te") {
+                        let te = std::str::from_utf8(header.value)?;
+                        te_gzip = Self::te_accepts(te, "gzip");
+                        te_trailers = Self::te_accepts(te, "trailers");
+                    } else if header.name.eq_ignore_ascii_case("accept") {
+                        error_format = Self::parse_accept(std::str::from_utf8(header.value)?);
+                    }
+                }
+
+                Ok(Some(ParseResult::Get {
+                    version,
+                    close,
+                    forwarded_for,
+                    head: method == "HEAD",
+                    upgrade: upgrade.map(|protocol| UpgradeRequest { protocol, websocket_key }),
+                    body_offset,
+                    body_len,
+                    request: GetRequest {
+                        path,
+                        host,
+                        query,
+                        ua,
+                        encoding,
+                        precompressed,
+                        te_gzip,
+                        te_trailers,
+                        error_format,
+                        conditions,
+                    },
+                }))
+            },
+            Some(method) => {
+                let (path, _) = uri::split_query(req.path.ok_or(httparse::Error::Token)?);
+                let path = path.to_string();
+                let mut close = version == http::Version::HTTP_10;
+                let mut host = None;
+                let mut content_range = None;
+                let mut content_type = None;
+                let mut conditions = WriteConditions::default();
+                let mut preflight_method = None;
+                let mut preflight_headers = None;
+                let mut error_format = ErrorFormat::Text;
+                for header in headers {
+                    if header.name.eq_ignore_ascii_case("connection") {
+                        close = Self::connection_closes(std::str::from_utf8(header.value)?, version);
+                    } else if header.name.eq_ignore_ascii_case("host") {
+                        host = Some(std::str::from_utf8(header.value)?.to_owned());
+                    } else if header.name.eq_ignore_ascii_case("content-range") {
+                        content_range = Some(std::str::from_utf8(header.value)?.to_owned());
+                    } else if header.name.eq_ignore_ascii_case("if-match") {
+                        conditions.if_match = Some(std::str::from_utf8(header.value)?.to_owned());
+                    } else if header.name.eq_ignore_ascii_case("if-none-match") {
+                        conditions.if_none_match = Some(std::str::from_utf8(header.value)?.to_owned());
+                    } else if header.name.eq_ignore_ascii_case("content-type") {
+                        content_type = Some(std::str::from_utf8(header.value)?.to_owned());
+                    } else if header.name.eq_ignore_ascii_case("access-control-request-method") {
+                        preflight_method = Some(std::str::from_utf8(header.value)?.trim().to_owned());
+                    } else if header.name.eq_ignore_ascii_case("access-control-request-headers") {
+                        preflight_headers = Some(std::str::from_utf8(header.value)?.trim().to_owned());
+                    } else if header.name.eq_ignore_ascii_case("accept") {
+                        error_format = Self::parse_accept(std::str::from_utf8(header.value)?);
+                    }
+                }
+
+                // Only PUT writes a part of a file, a POST is always taken
+                // to carry all of it.
+                let put = method == "PUT";
+                let range = match content_range {
+                    Some(range) if put => {
+                        match Self::parse_content_range(&range, &body_len) {
+                            Some(range) => Some(range),
+                            None => {
+                                info!("invalid content range {range}");
+                                return Ok(Some(ParseResult::Invalid {
+                                    status: http::StatusCode::BAD_REQUEST,
+                                    error_format,
+                                }));
+                            }
+                        }
                     }
+                    _ => None,
+                };
+
+                if method == "POST" || put {
+                    Ok(Some(ParseResult::Post {
+                        version,
+                        close,
+                        forwarded_for,
+                        body_offset,
+                        body_len,
+                        request: PostRequest { put, path, host, range, conditions, content_type, error_format },
+                    }))
+                } else {
+                    let preflight = preflight_method
+                        .filter(|_| method == "OPTIONS")
+                        .map(|method| Preflight { method, headers: preflight_headers });
+                    Ok(Some(ParseResult::Other {
+                        method: method.to_string(),
+                        path,
+                        version,
+                        close,
+                        forwarded_for,
+                        preflight,
+                        body_offset,
+                        body_len,
+                        error_format,
+                    }))
                 }
+            },
+            None => {
+                Err(httparse::Error::Token.into())
+            }
+        }
+    }
+
+    /// The request line and headers in `head` as handed to route handlers,
+    /// which get all of them rather than what the server picks out, bar
+    /// the hop-by-hop ones.
+    fn route_request(head: &[u8]) -> Result<http::Request<()>> {
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut req = httparse::Request::new(&mut headers);
+        req.parse(head)?;
+        let version = match req.version {
+            Some(0) => http::Version::HTTP_10,
+            _ => http::Version::HTTP_11,
+        };
+        let mut builder = http::Request::builder()
+            .method(req.method.unwrap_or_default())
+            .uri(req.path.unwrap_or_default())
+            .version(version);
+        for header in req.headers.iter() {
+            builder = builder.header(header.name, header.value);
+        }
+        let mut request = builder.body(()).map_err(|e| ServerError::Request(e.to_string()))?;
+        Self::strip_hop_by_hop(request.headers_mut());
+        Ok(request)
+    }
+
+    /// Removes the hop-by-hop headers, along with any `Connection` names.
+    fn strip_hop_by_hop(headers: &mut http::HeaderMap) {
+        let named: Vec<String> = headers.get_all(http::header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .collect();
+        for name in HOP_BY_HOP.iter().copied().chain(named.iter().map(String::as_str)) {
+            headers.remove(name);
+        }
+    }
+
+    /// Addresses from a request's `Forwarded` headers, or failing those its
+    /// `X-Forwarded-For` ones, in the order the request went through them.
+    /// Entries that aren't IP addresses, like `unknown`, are left out.
+    fn parse_forwarded_for(headers: &[httparse::Header]) -> Result<Vec<IpAddr>> {
+        let mut forwarded = vec![];
+        let mut x_forwarded_for = vec![];
+        for header in headers {
+            if header.name.eq_ignore_ascii_case("forwarded") {
+                for element in std::str::from_utf8(header.value)?.split(',') {
+                    let node = element.split(';')
+                        .filter_map(|pair| pair.trim().split_once('='))
+                        .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                        .and_then(|(_, node)| Self::parse_node(node.trim_matches('"')));
+                    forwarded.extend(node);
+                }
+            } else if header.name.eq_ignore_ascii_case("x-forwarded-for") {
+                x_forwarded_for.extend(std::str::from_utf8(header.value)?
+                                       .split(',')
+                                       .filter_map(|node| Self::parse_node(node.trim())));
+            }
+        }
+        Ok(if forwarded.is_empty() { x_forwarded_for } else { forwarded })
+    }
+
+    /// An address, possibly with a port, as in `192.0.2.1:80` or
+    /// `[2001:db8::1]:80`.
+    fn parse_node(node: &str) -> Option<IpAddr> {
+        if let Some(rest) = node.strip_prefix('[') {
+            return rest.split_once(']')?.0.parse().ok();
+        }
+        node.parse().ok().or_else(|| node.rsplit_once(':')?.0.parse().ok())
+    }
+
+    /// The client a request is made for. Only trusted proxies are believed
+    /// about the addresses they forward for, so the chain is followed back
+    /// from the peer for as long as it's made of trusted proxies.
+    fn client_ip(peer: IpAddr, forwarded_for: &[IpAddr], config: &ServerConfig) -> IpAddr {
+        let mut client = peer;
+        for addr in forwarded_for.iter().rev() {
+            if !config.trusted_proxies.contains(&client) {
+                break;
+            }
+            client = *addr;
+        }
+        client
+    }
+
+    /// Parses `bytes start-end/total`, which has to lie within the file and
+    /// agree with the body's length when that's known up front.
+    fn parse_content_range(range: &str, body_len: &BodyLength) -> Option<ContentRange> {
+        let (start, rest) = range.strip_prefix("bytes ")?.trim().split_once('-')?;
+        let (end, total) = rest.split_once('/')?;
+        let range = ContentRange {
+            start: start.parse().ok()?,
+            end: end.parse().ok()?,
+            total: total.parse().ok()?,
+        };
+        if range.start > range.end || range.end >= range.total {
+            return None;
+        }
+        match body_len {
+            BodyLength::Fixed(len) if *len as u64 != range.end - range.start + 1 => None,
+            _ => Some(range),
+        }
+    }
+
+    /// Whether a request's `Connection` header, a list of tokens, leaves the
+    /// connection to be closed after the response. HTTP/1.0 connections are
+    /// only kept open when asked for.
+    fn connection_closes(connection: &str, version: http::Version) -> bool {
+        let has = |token: &str| connection.split(',').any(|t| t.trim().eq_ignore_ascii_case(token));
+        if has("close") {
+            true
+        } else {
+            version == http::Version::HTTP_10 && !has("keep-alive")
+        }
+    }
+
+    /// How the request body's end is found. Two different `Content-Length`
+    /// values, or one alongside any `Transfer-Encoding`, are refused: a
+    /// proxy in front picking the other one is how requests get smuggled.
+    fn parse_body_length(headers: &[httparse::Header]) -> Result<BodyLength> {
+        let mut content_length = None;
+        let mut transfer_encoding = None;
+        for header in headers {
+            if header.name.eq_ignore_ascii_case("content-length") {
+                let len = std::str::from_utf8(header.value)?.trim().parse()?;
+                if content_length.is_some_and(|other| other != len) {
+                    return Err(ServerError::Request("conflicting Content-Length headers".to_string()));
+                }
+                content_length = Some(len);
+            } else if header.name.eq_ignore_ascii_case("transfer-encoding") {
+                transfer_encoding = Some(std::str::from_utf8(header.value)?);
+            }
+        }
+
+        match (content_length, transfer_encoding) {
+            (Some(_), Some(_)) => {
+                Err(ServerError::Request("both Content-Length and Transfer-Encoding".to_string()))
+            }
+            (_, Some(encoding)) => Self::parse_transfer_encoding(encoding),
+            (len, None) => Ok(BodyLength::Fixed(len.unwrap_or(0))),
+        }
+    }
+
+    /// Only bodies whose final transfer coding is `chunked` can be framed,
+    /// other codings are rejected.
+    fn parse_transfer_encoding(encoding: &str) -> Result<BodyLength> {
+        let last = encoding.rsplit(',').next().unwrap_or("").trim();
+        if last.eq_ignore_ascii_case("chunked") {
+            Ok(BodyLength::Chunked)
+        } else {
+            Err(ServerError::Request(format!("unsupported transfer encoding {encoding}")))
+        }
+    }
+
+    /// Errors are reported as JSON to clients accepting `application/json`
+    /// with a non-zero quality, and as plain text to everyone else.
+    fn parse_accept(accept: &str) -> ErrorFormat {
+        let accepts_json = accept.split(',').any(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or("");
+            let zero_q = params.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            media_type.eq_ignore_ascii_case("application/json") && !zero_q
+        });
+
+        if accepts_json {
+            ErrorFormat::Json
+        } else {
+            ErrorFormat::Text
+        }
+    }
+
+    /// The compression an `Accept-Encoding` header asks for, going by the
+    /// q-value of each coding. Compression only wins when it's accepted at
+    /// least as much as `identity` where that's given a q-value, directly or
+    /// through `*`, and gzip is preferred over deflate on a tie.
+    fn parse_encoding(encoding: &str) -> Option<HttpEncoding> {
+        let qualities = Self::coding_qualities(encoding);
+        let quality = |names: &[&str]| Self::coding_quality(&qualities, names).map_or(0.0, |(_, q)| q);
+
+        let identity = quality(&["identity"]);
+        let gzip = quality(&["gzip", "x-gzip"]);
+        let deflate = quality(&["deflate"]);
+        let (coding, q) = if gzip >= deflate {
+            (HttpEncoding::Gzip, gzip)
+        } else {
+            (HttpEncoding::Deflate, deflate)
+        };
+        (q > 0.0 && q >= identity).then_some(coding)
+    }
+
+    /// The codings listed in an `Accept-Encoding` header, in lower case,
+    /// with their q-values. Those with a malformed q-value are left out.
+    fn coding_qualities(accept: &str) -> Vec<(String, f32)> {
+        accept.to_ascii_lowercase()
+            .split(',')
+            .filter_map(|coding| {
+                let mut params = coding.split(';');
+                let name = params.next().unwrap_or_default().trim().to_string();
+                let q = match params.find_map(|p| p.trim().strip_prefix("q=")) {
+                    Some(q) => q.trim().parse().ok()?,
+                    None => 1.0,
+                };
+                Some((name, q))
+            })
+            .collect()
+    }
+
+    /// Where the first of `names` is listed and its q-value, falling back
+    /// on `*` when none of them is.
+    fn coding_quality(qualities: &[(String, f32)], names: &[&str]) -> Option<(usize, f32)> {
+        let find = |names: &[&str]| {
+            qualities.iter()
+                .position(|(name, _)| names.contains(&name.as_str()))
+                .map(|i| (i, qualities[i].1))
+        };
+        find(names).or_else(|| find(&["*"]))
+    }
 
-                Ok(Some(ParseResult::Get {
-                    close,
-                    path,
-                    ua,
-                    encoding,
-                }))
-            },
-            Some("POST") => {
-                let path = req.path
-                    .ok_or_eyre("missing request method")?
-                    .to_string();
-                let mut close = false;
-                let mut body_len: usize = 0;
-                for header in headers {
-                    if header.name.eq_ignore_ascii_case("connection") {
-                        close = std::str::from_utf8(header.value)?
-                            .eq_ignore_ascii_case("close");
-                    } else if header.name.eq_ignore_ascii_case("content-length") {
-                        body_len = std::str::from_utf8(header.value)?.parse()?;
-                    }
-                }
+    /// Whether a `TE` header accepts `coding`, a transfer coding or
+    /// `trailers`.
+    fn te_accepts(te: &str, coding: &str) -> bool {
+        te.split(',').any(|listed| {
+            let mut params = listed.split(';');
+            let accepted = params.next().unwrap_or_default().trim().eq_ignore_ascii_case(coding);
+            accepted && !params.any(|p| {
+                p.trim().strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok()) == Some(0.0)
+            })
+        })
+    }
 
-                Ok(Some(ParseResult::Post {
-                    close,
-                    path,
-                    body_offset,
-                    body_len,
-                }))
-            },
-            Some(method) => {
-                Err(eyre!("unsupported request method {method}"))
-            }
-            None => {
-                Err(eyre!("missing request method"))
+    /// The stored encodings an `Accept-Encoding` header accepts, most
+    /// preferred first by q-value and then by the order listed. Like with
+    /// [`Self::parse_encoding`], those accepted less than `identity` are
+    /// left out.
+    fn parse_precompressed(accept: &str) -> Vec<Precompressed> {
+        let qualities = Self::coding_qualities(accept);
+        let identity = Self::coding_quality(&qualities, &["identity"]).map_or(0.0, |(_, q)| q);
+        let mut accepted: Vec<_> = [
+            (Precompressed::Brotli, &["br"][..]),
+            (Precompressed::Gzip, &["gzip", "x-gzip"][..]),
+        ]
+            .into_iter()
+            .filter_map(|(stored, names)| {
+                let (listed, q) = Self::coding_quality(&qualities, names)?;
+                (q > 0.0 && q >= identity).then_some((stored, listed, q))
+            })
+            .collect();
+        accepted.sort_by(|(_, a_listed, a_q), (_, b_listed, b_q)| {
+            b_q.total_cmp(a_q).then(a_listed.cmp(b_listed))
+        });
+        accepted.into_iter().map(|(stored, ..)| stored).collect()
+    }
+
+    /// Maps the part of a `/files` request path after the prefix to a path
+    /// relative to the served directory, or to the status refusing it.
+    fn file_request_path(path: &str, config: &ServerConfig)
+                         -> std::result::Result<String, http::StatusCode> {
+        let Some(file_path) = Self::decode_file_path(path) else {
+            error!("invalid file path {path}");
+            return Err(http::StatusCode::BAD_REQUEST);
+        };
+
+        if config.is_hidden(&file_path) {
+            info!("refusing hidden file path {file_path}");
+            return Err(http::StatusCode::NOT_FOUND);
+        }
+
+        if config.is_denied(&file_path) {
+            info!("refusing denied file path {file_path}");
+            return Err(http::StatusCode::NOT_FOUND);
+        }
+
+        if Self::is_content_type_path(&file_path, config) {
+            info!("refusing content type sidecar path {file_path}");
+            return Err(http::StatusCode::NOT_FOUND);
+        }
+
+        Ok(file_path)
+    }
+
+    /// The first of `index_names` found in the directory at `path`, or
+    /// `path` itself when it's a file or there's no such index.
+    async fn index_path(files: &dyn FileSource, dir: &str, path: String, index_names: &[String])
+                        -> String {
+        if files.info(dir, &path).await.is_some() {
+            return path;
+        }
+        for name in index_names {
+            let index = format!("{}/{name}", path.trim_end_matches('/'));
+            if files.info(dir, &index).await.is_some() {
+                return index;
             }
         }
+        path
     }
 
-    fn parse_encoding(encoding: &str) -> Option<HttpEncoding> {
-        if encoding.to_ascii_lowercase().contains("gzip") {
-            Some(HttpEncoding::Gzip)
-        } else {
-            None
+    /// Percent-decodes the file path segment of a `/files` request.
+    ///
+    /// Returns `None` for malformed escapes, paths that don't decode to
+    /// UTF-8, and paths containing `..` components.
+    fn decode_file_path(path: &str) -> Option<String> {
+        let decoded = uri::percent_decode(path)?;
+        if decoded.split('/').any(|component| component == "..") {
+            return None;
         }
+        Some(decoded)
     }
 
     fn response200(body: Vec<u8>, cont_type: String, encoding: Option<HttpEncoding>) -> http::Response<Vec<u8>> {
         let res = http::response::Builder::new()
             .status(200)
-            .header("Content-length", body.len())
             .header("Content-type", cont_type);
-        let res = match encoding {
-            Some(HttpEncoding::Gzip) => {
-                res.header("Content-encoding", "gzip")
+        let (res, body) = match encoding {
+            Some(encoding) => {
+                let name = match encoding {
+                    HttpEncoding::Gzip => "gzip",
+                    HttpEncoding::Deflate | HttpEncoding::DeflateRaw => "deflate",
+                };
+                (res.header("Content-encoding", name), Self::compress(&body, encoding))
             }
-            None => res,
+            None => (res, body),
         };
-        res.body(body).unwrap()
+        res.header("Content-length", body.len()).body(body).unwrap()
+    }
+
+    fn compress(body: &[u8], encoding: HttpEncoding) -> Vec<u8> {
+        // Writing into a Vec can't fail.
+        fn encode<W: std::io::Write>(mut encoder: W, body: &[u8]) -> W {
+            encoder.write_all(body).unwrap();
+            encoder
+        }
+        match encoding {
+            HttpEncoding::Gzip => {
+                encode(GzEncoder::new(Vec::new(), Compression::default()), body).finish().unwrap()
+            }
+            HttpEncoding::Deflate => {
+                encode(ZlibEncoder::new(Vec::new(), Compression::default()), body).finish().unwrap()
+            }
+            HttpEncoding::DeflateRaw => {
+                encode(DeflateEncoder::new(Vec::new(), Compression::default()), body).finish().unwrap()
+            }
+        }
     }
 
     fn response200pt(body: Vec<u8>, encoding: Option<HttpEncoding>) -> http::Response<Vec<u8>> {
@@ -241,6 +2547,43 @@ impl BasicHttpServer {
         Self::response200(body, "application/octet-stream".to_string(), None)
     }
 
+    fn response200json(body: Vec<u8>) -> http::Response<Vec<u8>> {
+        Self::response200(body, "application/json".to_string(), None)
+    }
+
+    /// RFC 2324's answer to being asked for coffee.
+    fn response418() -> http::Response<Vec<u8>> {
+        let body = b"I'm a little teapot, short and stout.\n".to_vec();
+        http::response::Builder::new()
+            .status(http::StatusCode::IM_A_TEAPOT)
+            .header("Content-type", "text/plain")
+            .header("Content-length", body.len())
+            .body(body)
+            .unwrap()
+    }
+
+    fn response200gzip_chunked() -> http::Response<Vec<u8>> {
+        http::response::Builder::new()
+            .status(200)
+            .header("Content-type", "application/octet-stream")
+            .header("Content-encoding", "gzip")
+            .header("Transfer-encoding", "chunked")
+            .body(vec![])
+            .unwrap()
+    }
+
+    /// The same gzip stream as [`Self::response200gzip_chunked`], declared
+    /// as a transfer coding, which the client undoes before anything else
+    /// sees the body.
+    fn response200gzip_transfer() -> http::Response<Vec<u8>> {
+        http::response::Builder::new()
+            .status(200)
+            .header("Content-type", "application/octet-stream")
+            .header("Transfer-encoding", "gzip, chunked")
+            .body(vec![])
+            .unwrap()
+    }
+
     fn response201() -> http::Response<Vec<u8>> {
         http::response::Builder::new()
             .status(201)
@@ -249,29 +2592,244 @@ impl BasicHttpServer {
             .unwrap()
     }
 
-    fn response404() -> http::Response<Vec<u8>> {
+    /// Bare `status` with an empty body.
+    fn response_status(status: http::StatusCode) -> http::Response<Vec<u8>> {
+        let res = http::response::Builder::new().status(status);
+        let bodiless = status.is_informational()
+            || status == http::StatusCode::NO_CONTENT
+            || status == http::StatusCode::NOT_MODIFIED;
+        let res = if bodiless { res } else { res.header("Content-length", "0") };
+        res.body(vec![]).unwrap()
+    }
+
+    fn response_error(status: http::StatusCode, format: ErrorFormat) -> http::Response<Vec<u8>> {
+        let reason = status.canonical_reason().unwrap_or("");
+        let (body, cont_type) = match format {
+            ErrorFormat::Json => {
+                (format!("{{\"error\":{},\"status\":{}}}",
+                         json::string(&reason.to_ascii_lowercase()),
+                         status.as_u16()),
+                 "application/json")
+            }
+            ErrorFormat::Text => {
+                (format!("{} {reason}\n", status.as_u16()), "text/plain")
+            }
+        };
+
+        http::response::Builder::new()
+            .status(status)
+            .header("Content-length", body.len())
+            .header("Content-type", cont_type)
+            .body(body.into_bytes())
+            .unwrap()
+    }
+
+    /// Appends the request's method and path to a plain text 404 body, with
+    /// control characters left out so they can't mess with whatever shows it.
+    fn add_404_detail(resp: &mut http::Response<Vec<u8>>, method: &str, path: &str) {
+        let clean = |s: &str| s.chars().filter(|c| !c.is_control()).collect::<String>();
+        let detail = format!("{} {}\n", clean(method), clean(path));
+        resp.body_mut().extend_from_slice(detail.as_bytes());
+        let len = resp.body().len();
+        resp.headers_mut().insert(http::header::CONTENT_LENGTH, http::HeaderValue::from(len));
+    }
+
+    fn response304(etag: &str, last_modified: Option<SystemTime>) -> http::Response<Vec<u8>> {
+        let resp = http::response::Builder::new()
+            .status(304)
+            .body(vec![])
+            .unwrap();
+        Self::with_validators(resp, etag, last_modified)
+    }
+
+    fn response206(body: Vec<u8>, start: u64, end: u64, len: u64) -> http::Response<Vec<u8>> {
+        http::response::Builder::new()
+            .status(206)
+            .header("Content-length", body.len())
+            .header("Content-type", "application/octet-stream")
+            .header("Content-range", format!("bytes {start}-{end}/{len}"))
+            .body(body)
+            .unwrap()
+    }
+
+    fn response416(len: u64, format: ErrorFormat) -> http::Response<Vec<u8>> {
+        let mut resp = Self::response_error(http::StatusCode::RANGE_NOT_SATISFIABLE, format);
+        if let Ok(range) = http::HeaderValue::from_str(&format!("bytes */{len}")) {
+            resp.headers_mut().insert(http::header::CONTENT_RANGE, range);
+        }
+        resp
+    }
+
+    /// Adds a file's validators, and advertises byte ranges, which every
+    /// file response supports.
+    fn with_validators(mut resp: http::Response<Vec<u8>>,
+                       etag: &str,
+                       last_modified: Option<SystemTime>) -> http::Response<Vec<u8>> {
+        let headers = resp.headers_mut();
+        headers.insert(http::header::ACCEPT_RANGES, http::HeaderValue::from_static("bytes"));
+        if let Ok(etag) = http::HeaderValue::from_str(etag) {
+            headers.insert(http::header::ETAG, etag);
+        }
+        if let Some(Ok(date)) = last_modified
+            .map(|t| http::HeaderValue::from_str(&http_date::format(t))) {
+            headers.insert(http::header::LAST_MODIFIED, date);
+        }
+        resp
+    }
+
+    /// Lets caches keep the response for `max_age`, with `Expires` for
+    /// HTTP/1.0 caches.
+    fn add_cache_headers<T>(resp: &mut http::Response<T>, max_age: Duration, immutable: bool) {
+        let mut cache_control = format!("public, max-age={}", max_age.as_secs());
+        if immutable {
+            cache_control.push_str(", immutable");
+        }
+        let expires = http_date::format(SystemTime::now() + max_age);
+
+        let headers = resp.headers_mut();
+        if let Ok(value) = http::HeaderValue::from_str(&cache_control) {
+            headers.insert(http::header::CACHE_CONTROL, value);
+        }
+        if let Ok(value) = http::HeaderValue::from_str(&expires) {
+            headers.insert(http::header::EXPIRES, value);
+        }
+    }
+
+    /// Adds the `--header` headers configured for prefixes of `path`,
+    /// replacing any the response already has, after the CORS header.
+    fn add_route_headers<T>(config: &ServerConfig, path: &str, resp: &mut http::Response<T>) {
+        if config.cors {
+            resp.headers_mut().insert(http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                                      http::HeaderValue::from_static("*"));
+        }
+        for rule in &config.headers {
+            if strip_path_prefix(path, &rule.prefix).is_some() {
+                resp.headers_mut().insert(rule.name.clone(), rule.value.clone());
+            }
+        }
+    }
+
+    fn response101(protocol: &str) -> http::Response<Vec<u8>> {
         http::response::Builder::new()
-            .status(404)
-            .header("Content-length", "0")
+            .status(http::StatusCode::SWITCHING_PROTOCOLS)
+            .header(http::header::UPGRADE, protocol)
+            .header(http::header::CONNECTION, "Upgrade")
             .body(vec![])
             .unwrap()
     }
 
-    fn serialize_response<T>(resp: http::Response<T>) -> Vec<u8>
+    /// Early Hints, letting the client start on the linked resources while
+    /// the response is prepared. Like every 1xx response it has no body.
+    fn response103(links: &[&str]) -> http::Response<Vec<u8>> {
+        let mut resp = Self::response_status(http::StatusCode::from_u16(103).unwrap());
+        for link in links {
+            if let Ok(link) = http::HeaderValue::from_str(link) {
+                resp.headers_mut().append(http::header::LINK, link);
+            }
+        }
+        resp
+    }
+
+    fn response426(protocol: &str, format: ErrorFormat) -> http::Response<Vec<u8>> {
+        let mut resp = Self::response_error(http::StatusCode::UPGRADE_REQUIRED, format);
+        let headers = resp.headers_mut();
+        headers.insert(http::header::UPGRADE, http::HeaderValue::from_str(protocol).unwrap());
+        headers.insert(http::header::CONNECTION, http::HeaderValue::from_static("Upgrade"));
+        resp
+    }
+
+    fn response408() -> http::Response<Vec<u8>> {
+        let mut resp = Self::response_error(http::StatusCode::REQUEST_TIMEOUT, ErrorFormat::Text);
+        resp.headers_mut().insert(http::header::CONNECTION, http::HeaderValue::from_static("close"));
+        resp
+    }
+
+    /// Methods the server handles on `path`, as listed in `Allow`.
+    fn allowed_methods(state: &SharedState, path: &str) -> Vec<&'static str> {
+        let methods: &[&'static str] = if state.config.single_file.is_some() || state.files.read_only() {
+            &["GET", "HEAD"]
+        } else {
+            &["GET", "HEAD", "POST", "PUT"]
+        };
+        methods.iter()
+            .copied()
+            .filter(|method| state.config.method_allowed(path, method))
+            .collect()
+    }
+
+    fn response405(state: &SharedState, path: &str, format: ErrorFormat) -> http::Response<Vec<u8>> {
+        let mut resp = Self::response_error(http::StatusCode::METHOD_NOT_ALLOWED, format);
+        let allow = Self::allowed_methods(state, path).join(", ");
+        resp.headers_mut().insert(http::header::ALLOW, http::HeaderValue::from_str(&allow).unwrap());
+        resp
+    }
+
+    /// Allows the method and headers a preflight request asks for, as long
+    /// as the server handles the method, or the `--cors-allow-methods` list
+    /// when there is one. Without the method allowed the browser won't make
+    /// the actual request.
+    fn response_preflight(state: &SharedState, path: &str, preflight: Preflight)
+                          -> http::Response<Vec<u8>> {
+        let mut resp = Self::response_status(http::StatusCode::NO_CONTENT);
+        let headers = resp.headers_mut();
+        let configured = &state.config.cors_allow_methods;
+        if !configured.is_empty() {
+            if let Ok(methods) = http::HeaderValue::from_str(&configured.join(", ")) {
+                headers.insert(http::header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+            }
+        } else if Self::allowed_methods(state, path).contains(&preflight.method.as_str()) {
+            if let Ok(method) = http::HeaderValue::from_str(&preflight.method) {
+                headers.insert(http::header::ACCESS_CONTROL_ALLOW_METHODS, method);
+            }
+        }
+        if let Some(Ok(requested)) = preflight.headers.map(|h| http::HeaderValue::from_str(&h)) {
+            headers.insert(http::header::ACCESS_CONTROL_ALLOW_HEADERS, requested);
+        }
+        headers.insert(http::header::VARY, http::HeaderValue::from_static(
+            "Access-Control-Request-Method, Access-Control-Request-Headers"));
+        resp
+    }
+
+    /// Frames a handler's response by its body when the handler didn't,
+    /// so the connection can be kept alive after it. Statuses that never
+    /// have a body are left alone.
+    fn with_content_length(mut resp: http::Response<Vec<u8>>) -> http::Response<Vec<u8>> {
+        let status = resp.status();
+        let bodiless = status.is_informational()
+            || status == http::StatusCode::NO_CONTENT
+            || status == http::StatusCode::NOT_MODIFIED;
+        if !bodiless && !resp.headers().contains_key(http::header::CONTENT_LENGTH) {
+            let len = http::HeaderValue::from(resp.body().len());
+            resp.headers_mut().insert(http::header::CONTENT_LENGTH, len);
+        }
+        resp
+    }
+
+    /// The response as written on an HTTP/1 connection, with header names
+    /// in lower case as `http` keeps them, or Title-Case for clients that
+    /// mind.
+    fn serialize_response<T>(resp: http::Response<T>, title_case: bool) -> Vec<u8>
     where T: Into<Vec<u8>>{
         let mut serialized: Vec<u8> = Vec::new();
 
-        let status_line = format!("HTTP/1.1 {} {}\r\n",
+        let version = match resp.version() {
+            http::Version::HTTP_10 => "HTTP/1.0",
+            _ => "HTTP/1.1",
+        };
+        let status_line = format!("{} {} {}\r\n",
+                                  version,
                                   resp.status().as_u16(),
-                                  resp.status().canonical_reason().unwrap_or(""));
+                                  Self::reason_phrase(resp.status()));
         serialized.append(&mut status_line.into());
 
+        // Values may hold any bytes but controls, such as Latin-1 text
+        // from a route handler, so they're written out as they are.
         for (hname, hval) in resp.headers() {
-            serialized.append(&mut format!("{}: {}\r\n",
-                                           hname.as_str(),
-                                           hval.to_str()
-                                           .unwrap())
-                              .into());
+            let hname = if title_case { Self::title_case(hname.as_str()) } else { hname.to_string() };
+            serialized.extend_from_slice(hname.as_bytes());
+            serialized.extend_from_slice(b": ");
+            serialized.extend_from_slice(hval.as_bytes());
+            serialized.extend_from_slice(b"\r\n");
         }
         serialized.push(b'\r'); serialized.push(b'\n');
 
@@ -282,30 +2840,548 @@ impl BasicHttpServer {
         serialized
     }
 
-    async fn read_file(path: &str, dir: &str) -> Result<Vec<u8>> {
-        let mut file = File::open(format!("{dir}{path}")).await?;
+    /// Reason phrase of the status line, for the codes `http` has no name
+    /// for as well.
+    fn reason_phrase(status: http::StatusCode) -> &'static str {
+        match status.as_u16() {
+            103 => "Early Hints",
+            _ => status.canonical_reason().unwrap_or(""),
+        }
+    }
+
+    /// `content-length` as `Content-Length`.
+    fn title_case(name: &str) -> String {
+        name.split('-')
+            .map(|word| {
+                let mut chars = word.chars();
+                chars.next()
+                    .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// With symlink following disabled, refuses paths where any component
+    /// below `dir` is a symlink. Components that don't exist yet, as for an
+    /// upload, are fine.
+    async fn check_symlinks(dir: &str, path: &str, config: &ServerConfig)
+                            -> std::result::Result<(), http::StatusCode> {
+        if config.follow_symlinks {
+            return Ok(());
+        }
+
+        let mut resolved = PathBuf::from(dir);
+        for component in Path::new(path).components() {
+            if let Component::Normal(part) = component {
+                resolved.push(part);
+                match tokio::fs::symlink_metadata(&resolved).await {
+                    Ok(metadata) if metadata.file_type().is_symlink() => {
+                        info!("refusing path through symlink {}", resolved.display());
+                        return Err(http::StatusCode::NOT_FOUND);
+                    }
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            }
+        }
+
+        // Belt and braces: whatever exists must still resolve inside `dir`.
+        match tokio::fs::canonicalize(&resolved).await {
+            Ok(canonical) if !canonical.starts_with(dir) => {
+                info!("refusing path escaping {dir}: {}", canonical.display());
+                Err(http::StatusCode::NOT_FOUND)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[tracing::instrument(skip(files), fields(bytes))]
+    async fn read_file(files: &dyn FileSource, path: &str, dir: &str) -> Result<Vec<u8>> {
+        let mut file = files.open(dir, path).await?;
         let mut contents = vec![];
         file.read_to_end(&mut contents).await?;
+        Span::current().record("bytes", contents.len());
+        Ok(contents)
+    }
+
+    /// Reads bytes `start` through `end`, inclusive.
+    #[tracing::instrument(skip(files), fields(bytes))]
+    async fn read_file_range(files: &dyn FileSource,
+                             path: &str,
+                             dir: &str,
+                             start: u64,
+                             end: u64) -> Result<Vec<u8>> {
+        let mut file = files.open(dir, path).await?;
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        let mut contents = vec![];
+        file.take(end - start + 1).read_to_end(&mut contents).await?;
+        Span::current().record("bytes", contents.len());
         Ok(contents)
     }
 
-    async fn write_file(stream: &mut TcpStream,
+    /// Sends `file` as a chunked body, compressing it piece by piece so
+    /// that it never has to be held in memory as a whole. Given a
+    /// `checksum`, it follows the last chunk as a trailer.
+    async fn write_gzip_chunked(stream: &mut TcpStream,
+                                mut file: FileReader,
+                                read_size: usize,
+                                throttle: &mut Option<Throttle>,
+                                mut checksum: Option<Checksum>) -> Result<u64> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        let mut read_buf = vec![0u8; read_size];
+        let mut written = 0;
+        loop {
+            let n = file.read(&mut read_buf).await?;
+            if n == 0 {
+                break;
+            }
+
+            encoder.write_all(&read_buf[..n])?;
+            match &mut checksum {
+                Some(Checksum { md5, encoded: true }) => md5.update(encoder.get_ref()),
+                Some(Checksum { md5, encoded: false }) => md5.update(&read_buf[..n]),
+                None => (),
+            }
+            written += Self::write_chunk(stream, encoder.get_mut(), throttle).await?;
+        }
+
+        let mut tail = encoder.finish()?;
+        if let Some(Checksum { md5, encoded: true }) = &mut checksum {
+            md5.update(&tail);
+        }
+        written += Self::write_chunk(stream, &mut tail, throttle).await?;
+        let last = match checksum {
+            Some(Checksum { md5, .. }) => format!("0\r\nContent-MD5: {}\r\n\r\n", base64(&md5.finish())),
+            None => "0\r\n\r\n".to_string(),
+        };
+        throttle::write_all(stream, last.as_bytes(), throttle.as_mut()).await?;
+        Ok(written + last.len() as u64)
+    }
+
+    /// Writes out and clears `data` as a single chunk, returning the bytes
+    /// written with framing. Nothing is written when it's empty since a
+    /// zero-sized chunk would end the body.
+    async fn write_chunk(stream: &mut TcpStream,
+                         data: &mut Vec<u8>,
+                         throttle: &mut Option<Throttle>) -> Result<u64> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let size = format!("{:x}\r\n", data.len());
+        throttle::write_all(stream, size.as_bytes(), throttle.as_mut()).await?;
+        throttle::write_all(stream, data, throttle.as_mut()).await?;
+        throttle::write_all(stream, b"\r\n", throttle.as_mut()).await?;
+        let written = size.len() + data.len() + 2;
+        data.clear();
+        Ok(written as u64)
+    }
+
+    /// Sidecar file holding the content type an upload was made with.
+    fn content_type_path(path: &str) -> String {
+        format!("{path}.meta")
+    }
+
+    /// Whether `path` is a sidecar written with `--store-content-type`,
+    /// which clients may neither read nor overwrite.
+    fn is_content_type_path(path: &str, config: &ServerConfig) -> bool {
+        config.store_content_type && path.ends_with(".meta")
+    }
+
+    /// Records the content type of the file at `path`, or forgets it when
+    /// there's none.
+    async fn store_content_type(dir: &str, path: &str, cont_type: Option<&str>) -> Result<()> {
+        let meta_path = resolve_path(dir, &Self::content_type_path(path));
+        match cont_type {
+            Some(cont_type) => tokio::fs::write(meta_path, cont_type).await?,
+            None => match tokio::fs::remove_file(meta_path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            },
+        }
+        Ok(())
+    }
+
+    /// The content type the file at `path` was uploaded with, if known.
+    async fn stored_content_type(files: &dyn FileSource, dir: &str, path: &str)
+                                 -> Option<http::HeaderValue> {
+        let mut meta = files.open(dir, &Self::content_type_path(path)).await.ok()?;
+        let mut cont_type = String::new();
+        meta.read_to_string(&mut cont_type).await.ok()?;
+        http::HeaderValue::from_str(cont_type.trim()).ok()
+    }
+
+    /// Reads a body to send back from `/echo`, or `None` once it's known to
+    /// be longer than `max` bytes.
+    async fn read_echo_body(mut body: BodyReader<'_>, body_len: BodyLength, max: usize)
+                            -> Result<Option<Vec<u8>>> {
+        if matches!(body_len, BodyLength::Fixed(len) if len > max) {
+            return Ok(None);
+        }
+        let mut contents = vec![];
+        while let Some(chunk) = body.chunk().await? {
+            if contents.len() + chunk.len() > max {
+                return Ok(None);
+            }
+            contents.extend_from_slice(&chunk);
+        }
+        Ok(Some(contents))
+    }
+
+    /// Writes the files in a form to the directory at `dir_path`, returning
+    /// their names. Other fields, and files the config refuses, are left
+    /// out, as are files named after a symlink when those aren't followed.
+    async fn write_multipart(mut form: Multipart<'_>, dir: &str, dir_path: &str, config: &ServerConfig)
+                             -> Result<Vec<String>> {
+        let mut written = vec![];
+        while let Some(part) = form.next_part().await? {
+            let Some(filename) = part.filename else {
+                info!("skipping form field {}", part.name.unwrap_or_default());
+                continue;
+            };
+            // Some browsers send the whole path the file had on the client.
+            let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+            let file_path = format!("{}/{name}", dir_path.trim_end_matches('/'));
+            if matches!(name, "" | "." | "..") || config.is_hidden(&file_path) || config.is_denied(&file_path)
+                || Self::is_content_type_path(&file_path, config)
+                || Self::check_symlinks(dir, &file_path, config).await.is_err() {
+                info!("skipping form file {filename:?}");
+                continue;
+            }
+
+            let path = resolve_path(dir, &file_path);
+            if let Some(parent) = path.parent().filter(|_| config.create_parents) {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut file = File::create(path).await?;
+            Self::set_file_mode(&file, config.file_mode).await?;
+            while let Some(chunk) = form.chunk().await? {
+                file.write_all(&chunk).await?;
+            }
+            // Writes finish in the background until flushed.
+            file.flush().await?;
+            written.push(name.to_string());
+        }
+        Ok(written)
+    }
+
+    /// Sets the permission bits of an uploaded file. Only Unix has them.
+    async fn set_file_mode(file: &File, mode: Option<u32>) -> Result<()> {
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(mode)).await?;
+        }
+        #[cfg(not(unix))]
+        let _ = (file, mode);
+        Ok(())
+    }
+
+    /// Writes the body to the file, replacing it or, given a `range`,
+    /// writing just that region of it. Returns the number of bytes written.
+    /// Directories leading to it are created first with `create_parents`,
+    /// and the file is given `mode` when set.
+    #[tracing::instrument(skip_all, fields(path = %resolve_path(dir, path).display(), bytes))]
+    async fn write_file(mut body: BodyReader<'_>,
                         path: &str,
                         dir: &str,
-                        content_prefix: &[u8],
-                        content_len: usize) -> Result<()> {
-        let mut file = File::create(format!("{dir}{path}")).await?;
-        file.write_all(content_prefix).await?;
+                        range: Option<&ContentRange>,
+                        create_parents: bool,
+                        mode: Option<u32>) -> Result<u64> {
+        let path = resolve_path(dir, path);
+        // The path is already confined to `dir`, so its parents are too.
+        if let Some(parent) = path.parent().filter(|_| create_parents) {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let Some(range) = range else {
+            let mut file = File::create(path).await?;
+            Self::set_file_mode(&file, mode).await?;
+            let mut written = 0;
+            while let Some(chunk) = body.chunk().await? {
+                file.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+            }
+            file.flush().await?;
+            Span::current().record("bytes", written);
+            return Ok(written);
+        };
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await?;
+        Self::set_file_mode(&file, mode).await?;
+        if file.metadata().await?.len() > range.total {
+            file.set_len(range.total).await?;
+        }
+        file.seek(std::io::SeekFrom::Start(range.start)).await?;
 
-        let mut content_len = content_len - content_prefix.len();
-        while content_len > 0 {
-            let mut content_buf = vec![0u8; std::cmp::min(content_len, 65536)];
-            let n = stream.read(&mut content_buf).await?;
-            file.write_all(&content_buf[..n]).await?;
+        // A chunked body isn't checked against the range up front.
+        let mut remaining = range.end - range.start + 1;
+        while let Some(chunk) = body.chunk().await? {
+            if chunk.len() as u64 > remaining {
+                return Err(ServerError::Request("request body longer than its content range".to_string()));
+            }
+            remaining -= chunk.len() as u64;
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        let written = range.end - range.start + 1 - remaining;
+        Span::current().record("bytes", written);
+
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_HEADER_SIZE: usize = 8192;
+
+    fn parse(request: &str) -> ParseResult {
+        BasicHttpServer::parse_request(request.as_bytes(), MAX_HEADER_SIZE)
+            .unwrap()
+            .expect("request should be complete")
+    }
+
+    #[test]
+    fn get_without_encoding() {
+        let raw = "GET /echo/abc HTTP/1.1\r\nHost: localhost\r\nUser-Agent: curl/8.0\r\n\r\n";
+        let ParseResult::Get { version, close, head, body_offset, request, .. } = parse(raw) else {
+            panic!("not parsed as a GET");
+        };
+        assert_eq!(version, http::Version::HTTP_11);
+        assert!(!close);
+        assert!(!head);
+        assert_eq!(body_offset, raw.len());
+        assert_eq!(request.path, "/echo/abc");
+        assert_eq!(request.host.as_deref(), Some("localhost"));
+        assert_eq!(request.ua.as_deref(), Some("curl/8.0"));
+        assert!(request.encoding.is_none());
+        assert!(request.precompressed.is_empty());
+    }
+
+    #[test]
+    fn get_with_encoding() {
+        let ParseResult::Get { request, .. } =
+            parse("GET /files/a HTTP/1.1\r\nHost: x\r\nAccept-Encoding: br, gzip\r\n\r\n") else {
+                panic!("not parsed as a GET");
+            };
+        assert!(matches!(request.encoding, Some(HttpEncoding::Gzip)));
+        assert!(matches!(request.precompressed[..], [Precompressed::Brotli, Precompressed::Gzip]));
+    }
 
-            content_len -= n;
+    #[test]
+    fn encoding_follows_q_values() {
+        let encoding = BasicHttpServer::parse_encoding;
+        assert!(matches!(encoding("gzip"), Some(HttpEncoding::Gzip)));
+        assert!(matches!(encoding("deflate, gzip"), Some(HttpEncoding::Gzip)));
+        assert!(matches!(encoding("gzip;q=0.5, deflate"), Some(HttpEncoding::Deflate)));
+        assert!(matches!(encoding("GZIP; Q=0.8"), Some(HttpEncoding::Gzip)));
+        assert!(matches!(encoding("*"), Some(HttpEncoding::Gzip)));
+        assert!(matches!(encoding("gzip;q=0.5, identity;q=0.5"), Some(HttpEncoding::Gzip)));
+        assert!(encoding("gzip;q=0.1, identity;q=0.9").is_none());
+        assert!(matches!(encoding("gzip;q=0.5, *;q=0.8"), Some(HttpEncoding::Deflate)));
+        assert!(encoding("gzip;q=0.5, identity;q=0.8, *;q=0").is_none());
+        assert!(encoding("gzip;q=0").is_none());
+        assert!(encoding("br, identity").is_none());
+        assert!(encoding("").is_none());
+    }
+
+    #[test]
+    fn precompressed_follows_q_values() {
+        use Precompressed::{Brotli, Gzip};
+        let stored = BasicHttpServer::parse_precompressed;
+        assert_eq!(stored("br;q=0.1, gzip;q=0.9"), [Gzip, Brotli]);
+        assert_eq!(stored("gzip, br"), [Gzip, Brotli]);
+        assert_eq!(stored("br, gzip;q=0.5"), [Brotli, Gzip]);
+        assert_eq!(stored("BR;Q=0, gzip"), [Gzip]);
+        assert_eq!(stored("br;q=0.4, identity;q=0.5, gzip"), [Gzip]);
+        assert_eq!(stored("deflate"), []);
+    }
+
+    #[test]
+    fn if_match_compares_strongly() {
+        let info = FileInfo { len: 5, modified: None };
+        let etag = BasicHttpServer::etag(&info);
+        let holds = |if_match: &str| {
+            let conditions = WriteConditions { if_match: Some(if_match.to_string()), if_none_match: None };
+            BasicHttpServer::write_preconditions_hold(&conditions, Some(&info))
+        };
+        assert!(holds(&etag));
+        assert!(holds(&format!("\"other\", {etag}")));
+        assert!(holds("*"));
+        assert!(!holds(&format!("W/{etag}")));
+        // If-None-Match compares weakly.
+        assert!(BasicHttpServer::etag_matches(&format!("W/{etag}"), &etag));
+    }
+
+    #[test]
+    fn accept_errors_are_told_apart() {
+        use std::io::{Error, ErrorKind};
+        let fatal = |err: &Error| BasicHttpServer::accept_error_is_fatal(err);
+        let transient = |err: &Error| BasicHttpServer::accept_error_is_transient(err);
+        for kind in [ErrorKind::InvalidInput, ErrorKind::NotConnected, ErrorKind::Unsupported] {
+            let err = Error::from(kind);
+            assert!(fatal(&err) && !transient(&err), "{kind:?}");
+        }
+        for kind in [ErrorKind::ConnectionAborted, ErrorKind::ConnectionReset, ErrorKind::Interrupted] {
+            let err = Error::from(kind);
+            assert!(!fatal(&err) && transient(&err), "{kind:?}");
         }
+        // Running out of file descriptors is neither, and is backed off from.
+        #[cfg(unix)]
+        {
+            let emfile = Error::from_raw_os_error(24);
+            assert!(!fatal(&emfile) && !transient(&emfile));
+        }
+    }
 
-        Ok(())
+    #[test]
+    fn only_byte_ranges_are_honoured() {
+        let range = |spec| BasicHttpServer::parse_range(spec, 100);
+        assert!(matches!(range("bytes=0-9"), RangeRequest::Satisfiable(0, 9)));
+        assert!(matches!(range("Bytes=90-"), RangeRequest::Satisfiable(90, 99)));
+        assert!(matches!(range("bytes=-10"), RangeRequest::Satisfiable(90, 99)));
+        assert!(matches!(range("bytes=100-"), RangeRequest::Unsatisfiable));
+        assert!(matches!(range("items=0-10"), RangeRequest::Ignored));
+        assert!(matches!(range("items=100-"), RangeRequest::Ignored));
+        assert!(matches!(range("bytesx=0-10"), RangeRequest::Ignored));
+        assert!(matches!(range("0-10"), RangeRequest::Ignored));
+        assert!(matches!(range("bytes=0-1,5-6"), RangeRequest::Ignored));
+    }
+
+    #[test]
+    fn get_splits_off_the_query() {
+        let ParseResult::Get { request, .. } =
+            parse("GET /echo/abc?format=upper HTTP/1.1\r\nHost: x\r\n\r\n") else {
+                panic!("not parsed as a GET");
+            };
+        assert_eq!(request.path, "/echo/abc");
+        assert_eq!(request.query.as_deref(), Some("format=upper"));
+    }
+
+    #[test]
+    fn head_is_a_get_without_body() {
+        let ParseResult::Get { head, .. } = parse("HEAD / HTTP/1.1\r\nHost: x\r\n\r\n") else {
+            panic!("not parsed as a GET");
+        };
+        assert!(head);
+    }
+
+    #[test]
+    fn http10_closes_by_default() {
+        let ParseResult::Get { version, close, .. } = parse("GET / HTTP/1.0\r\n\r\n") else {
+            panic!("not parsed as a GET");
+        };
+        assert_eq!(version, http::Version::HTTP_10);
+        assert!(close);
+    }
+
+    #[test]
+    fn post_with_content_length() {
+        let request = "POST /files/a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello";
+        let ParseResult::Post { request: post, body_offset, body_len, .. } = parse(request) else {
+            panic!("not parsed as a POST");
+        };
+        assert!(!post.put);
+        assert_eq!(post.path, "/files/a.txt");
+        assert_eq!(&request[body_offset..], "hello");
+        assert!(matches!(body_len, BodyLength::Fixed(5)));
+    }
+
+    #[test]
+    fn post_chunked() {
+        let ParseResult::Post { body_len, .. } =
+            parse("POST /files/a HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n") else {
+                panic!("not parsed as a POST");
+            };
+        assert!(matches!(body_len, BodyLength::Chunked));
+    }
+
+    #[test]
+    fn post_with_conflicting_lengths_is_an_error() {
+        let request = "POST /files/a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n";
+        assert!(BasicHttpServer::parse_request(request.as_bytes(), MAX_HEADER_SIZE).is_err());
+        let request = "POST /files/a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\
+                       Transfer-Encoding: chunked\r\n\r\n";
+        assert!(BasicHttpServer::parse_request(request.as_bytes(), MAX_HEADER_SIZE).is_err());
+    }
+
+    #[test]
+    fn put_with_content_range() {
+        let request = "PUT /files/a HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\
+                       Content-Range: bytes 2-5/10\r\n\r\n";
+        let ParseResult::Post { request: PostRequest { put, range: Some(range), .. }, .. } = parse(request) else {
+            panic!("not parsed as a ranged PUT");
+        };
+        assert!(put);
+        assert_eq!((range.start, range.end, range.total), (2, 5, 10));
+    }
+
+    #[test]
+    fn unsupported_method_is_other() {
+        let ParseResult::Other { method, path, preflight, .. } =
+            parse("DELETE /files/a HTTP/1.1\r\nHost: x\r\n\r\n") else {
+                panic!("not parsed as another method");
+            };
+        assert_eq!(method, "DELETE");
+        assert_eq!(path, "/files/a");
+        assert!(preflight.is_none());
+    }
+
+    #[test]
+    fn options_preflight() {
+        let request = "OPTIONS /files/a HTTP/1.1\r\nHost: x\r\nAccess-Control-Request-Method: PUT\r\n\
+                       Access-Control-Request-Headers: content-type\r\n\r\n";
+        let ParseResult::Other { preflight: Some(preflight), .. } = parse(request) else {
+            panic!("not parsed as a preflight");
+        };
+        assert_eq!(preflight.method, "PUT");
+        assert_eq!(preflight.headers.as_deref(), Some("content-type"));
+    }
+
+    #[test]
+    fn partial_request_needs_more() {
+        for partial in ["", "GET", "GET / HTTP/1.1\r\n", "GET / HTTP/1.1\r\nHost: x\r\n"] {
+            let parsed = BasicHttpServer::parse_request(partial.as_bytes(), MAX_HEADER_SIZE).unwrap();
+            assert!(parsed.is_none(), "{partial:?} parsed as complete");
+        }
+    }
+
+    #[test]
+    fn oversized_headers_are_refused() {
+        let request = format!("GET / HTTP/1.1\r\nHost: x\r\nX-Padding: {}\r\n\r\n", "a".repeat(100));
+        let parsed = BasicHttpServer::parse_request(request.as_bytes(), 64).unwrap();
+        assert!(matches!(parsed, Some(ParseResult::Invalid {
+            status: http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, ..
+        })));
+        // Still partial, but already too long to ever fit.
+        let parsed = BasicHttpServer::parse_request(&request.as_bytes()[..80], 64).unwrap();
+        assert!(matches!(parsed, Some(ParseResult::Invalid { .. })));
+    }
+
+    #[test]
+    fn missing_host_is_a_bad_request() {
+        assert!(matches!(parse("GET / HTTP/1.1\r\n\r\n"), ParseResult::Invalid {
+            status: http::StatusCode::BAD_REQUEST, ..
+        }));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        assert!(matches!(parse("GET / HTTP/2.0\r\nHost: x\r\n\r\n"), ParseResult::Invalid {
+            status: http::StatusCode::HTTP_VERSION_NOT_SUPPORTED, ..
+        }));
+    }
+
+    #[test]
+    fn malformed_header_is_an_error() {
+        let request = b"GET / HTTP/1.1\r\nHost: x\r\nNo colon here\r\n\r\n";
+        assert!(BasicHttpServer::parse_request(request, MAX_HEADER_SIZE).is_err());
     }
 }
@@ -0,0 +1,232 @@
+use crate::error::{Result, ServerError};
+use crate::server_config::ServerConfig;
+use tracing::*;
+use tokio::net::TcpStream;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use bytes::{Buf, Bytes, BytesMut};
+
+/// How the end of a request body is determined.
+#[derive(Clone, Copy)]
+pub(crate) enum BodyLength {
+    Fixed(usize),
+    Chunked,
+}
+
+/// Request body handed to custom handlers, yielded piece by piece as it
+/// arrives on the connection.
+pub struct Body {
+    chunks: mpsc::Receiver<Result<Bytes>>,
+}
+
+impl Body {
+    /// Next piece of the body, or `None` once all of it has been read.
+    pub async fn chunk(&mut self) -> Option<Result<Bytes>> {
+        self.chunks.recv().await
+    }
+
+    /// Creates a body along with the sender feeding it.
+    pub(crate) fn channel() -> (mpsc::Sender<Result<Bytes>>, Body) {
+        let (tx, chunks) = mpsc::channel(8);
+        (tx, Body { chunks })
+    }
+
+    /// Forwards everything `reader` yields to the receiving [`Body`]. The
+    /// rest of the body is still consumed if the receiver goes away early,
+    /// so the connection stays positioned at the next request.
+    pub(crate) async fn pump(mut reader: BodyReader<'_>,
+                             tx: mpsc::Sender<Result<Bytes>>) -> Result<()> {
+        let mut receiver_gone = false;
+        loop {
+            match reader.chunk().await {
+                Ok(Some(chunk)) => {
+                    if !receiver_gone && tx.send(Ok(chunk)).await.is_err() {
+                        info!("body receiver dropped, discarding the rest");
+                        receiver_gone = true;
+                    }
+                }
+                Ok(None) => return Ok(()),
+                Err(err) => {
+                    let _ = tx.send(Err(ServerError::Request(format!("request body read error: {err}"))))
+                        .await;
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+enum ReadState {
+    Fixed(usize),
+    ChunkSize,
+    ChunkData(usize),
+    Done,
+}
+
+/// Reads a framed request body off the connection, starting with whatever
+/// is already buffered and leaving any bytes past its end in the buffer.
+pub(crate) struct BodyReader<'a> {
+    stream: &'a mut TcpStream,
+    buf: &'a mut BytesMut,
+    state: ReadState,
+    /// Room made in `buf` for each read off the stream.
+    read_size: usize,
+    /// Largest trailer section accepted after the last chunk.
+    max_trailer_size: usize,
+    /// Largest chunked body accepted, checked as each chunk starts.
+    max_len: u64,
+    /// Body bytes announced by the chunks so far.
+    read: u64,
+    /// Bytes of the body still to be logged as they're read.
+    dump: usize,
+}
+
+impl<'a> BodyReader<'a> {
+    pub(crate) fn new(stream: &'a mut TcpStream,
+                      buf: &'a mut BytesMut,
+                      body_len: BodyLength,
+                      config: &ServerConfig) -> BodyReader<'a> {
+        let state = match body_len {
+            BodyLength::Fixed(len) => ReadState::Fixed(len),
+            BodyLength::Chunked => ReadState::ChunkSize,
+        };
+        BodyReader {
+            stream,
+            buf,
+            state,
+            read_size: config.io_buffer_size,
+            max_trailer_size: config.max_header_size,
+            max_len: config.max_body_size,
+            read: 0,
+            dump: 0,
+        }
+    }
+
+    /// Logs up to the first `len` bytes of the body at trace level as
+    /// they're read, without holding on to them.
+    pub(crate) fn dump_prefix(mut self, len: usize) -> BodyReader<'a> {
+        self.dump = len;
+        self
+    }
+
+    /// Next piece of the body, or `None` once all of it has been read.
+    pub(crate) async fn chunk(&mut self) -> Result<Option<Bytes>> {
+        loop {
+            match self.state {
+                ReadState::Fixed(0) | ReadState::Done => {
+                    self.state = ReadState::Done;
+                    return Ok(None);
+                }
+                ReadState::Fixed(remaining) => {
+                    let chunk = self.take(remaining).await?;
+                    self.state = ReadState::Fixed(remaining - chunk.len());
+                    self.dump(&chunk);
+                    return Ok(Some(chunk));
+                }
+                ReadState::ChunkSize => {
+                    let (offset, chunk_len) = loop {
+                        match httparse::parse_chunk_size(self.buf) {
+                            Ok(httparse::Status::Complete(res)) => break res,
+                            Ok(httparse::Status::Partial) => self.fill_buf().await?,
+                            Err(_) => return Err(ServerError::Request("invalid chunk size".to_string())),
+                        }
+                    };
+                    self.buf.advance(offset);
+
+                    self.read = self.read.saturating_add(chunk_len);
+                    if self.read > self.max_len {
+                        return Err(ServerError::BodyTooLarge(self.max_len));
+                    }
+                    if chunk_len == 0 {
+                        self.skip_trailers().await?;
+                        self.state = ReadState::Done;
+                    } else {
+                        self.state = ReadState::ChunkData(chunk_len.try_into()?);
+                    }
+                }
+                ReadState::ChunkData(0) => {
+                    while self.buf.len() < 2 {
+                        self.fill_buf().await?;
+                    }
+                    if &self.buf[..2] != b"\r\n" {
+                        return Err(ServerError::Request("missing CRLF after chunk data".to_string()));
+                    }
+                    self.buf.advance(2);
+                    self.state = ReadState::ChunkSize;
+                }
+                ReadState::ChunkData(remaining) => {
+                    let chunk = self.take(remaining).await?;
+                    self.state = ReadState::ChunkData(remaining - chunk.len());
+                    self.dump(&chunk);
+                    return Ok(Some(chunk));
+                }
+            }
+        }
+    }
+
+    /// Reads and throws away the rest of the body.
+    pub(crate) async fn drain(mut self) -> Result<()> {
+        while self.chunk().await?.is_some() {}
+        Ok(())
+    }
+
+    fn dump(&mut self, chunk: &[u8]) {
+        if self.dump > 0 {
+            let len = std::cmp::min(self.dump, chunk.len());
+            trace!("request body: {}", chunk[..len].escape_ascii());
+            self.dump -= len;
+        }
+    }
+
+    /// Takes up to `max` bytes, reading from the stream only if nothing
+    /// is buffered.
+    async fn take(&mut self, max: usize) -> Result<Bytes> {
+        if self.buf.is_empty() {
+            self.fill_buf().await?;
+        }
+
+        let n = std::cmp::min(max, self.buf.len());
+        Ok(self.buf.split_to(n).freeze())
+    }
+
+    /// Consumes the trailer section following the last chunk so that the
+    /// next pipelined request starts at the beginning of the buffer. It's
+    /// held to the same size as the request's header section.
+    async fn skip_trailers(&mut self) -> Result<()> {
+        let max = self.max_trailer_size;
+        let too_long = || ServerError::Request(format!("trailer section over {max} bytes"));
+        loop {
+            let mut trailers = [httparse::EMPTY_HEADER; 16];
+            let offset = match httparse::parse_headers(self.buf, &mut trailers)? {
+                httparse::Status::Complete((offset, _)) if offset > max => {
+                    return Err(too_long());
+                }
+                httparse::Status::Complete((offset, trailers)) => {
+                    for trailer in trailers {
+                        info!("discarding trailer {}", trailer.name);
+                    }
+                    offset
+                }
+                httparse::Status::Partial if self.buf.len() > max => {
+                    return Err(too_long());
+                }
+                httparse::Status::Partial => {
+                    self.fill_buf().await?;
+                    continue;
+                }
+            };
+
+            self.buf.advance(offset);
+            return Ok(());
+        }
+    }
+
+    async fn fill_buf(&mut self) -> Result<()> {
+        self.buf.reserve(self.read_size);
+        if self.stream.read_buf(self.buf).await? == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof,
+                                           "connection closed before end of request body").into());
+        }
+        Ok(())
+    }
+}
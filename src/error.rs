@@ -0,0 +1,69 @@
+use thiserror::Error;
+
+/// Errors surfaced by the server, from startup and from serving connections.
+#[derive(Debug, Error)]
+pub enum ServerError {
+    /// The listening socket couldn't be bound.
+    #[error("can't bind {addr}: {source}")]
+    Bind {
+        addr: String,
+        source: std::io::Error,
+    },
+    /// A configured path doesn't exist or can't be served.
+    #[error("invalid path {path}: {reason}")]
+    Path {
+        path: String,
+        reason: String,
+    },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// A request line or headers that don't parse.
+    #[error("malformed request: {0}")]
+    Parse(#[from] httparse::Error),
+    /// A request that parses but can't be served, such as one with an
+    /// unsupported transfer coding or a broken body.
+    #[error("bad request: {0}")]
+    Request(String),
+    /// A request body running past the configured limit as it's read.
+    #[error("request body over {0} bytes")]
+    BodyTooLarge(u64),
+    #[error(transparent)]
+    Http2(#[from] h2::Error),
+}
+
+impl ServerError {
+    /// Whether this is the client going away, a routine end to a
+    /// connection rather than a failure.
+    pub fn is_disconnect(&self) -> bool {
+        matches!(self, ServerError::Io(err) if is_disconnect(err))
+    }
+}
+
+/// Whether an I/O error on a connection means the peer closed it.
+pub(crate) fn is_disconnect(err: &std::io::Error) -> bool {
+    matches!(err.kind(),
+             std::io::ErrorKind::BrokenPipe
+             | std::io::ErrorKind::ConnectionReset
+             | std::io::ErrorKind::ConnectionAborted
+             | std::io::ErrorKind::UnexpectedEof)
+}
+
+impl From<std::str::Utf8Error> for ServerError {
+    fn from(err: std::str::Utf8Error) -> ServerError {
+        ServerError::Request(format!("header value is not valid UTF-8: {err}"))
+    }
+}
+
+impl From<std::num::ParseIntError> for ServerError {
+    fn from(err: std::num::ParseIntError) -> ServerError {
+        ServerError::Request(format!("invalid number: {err}"))
+    }
+}
+
+impl From<std::num::TryFromIntError> for ServerError {
+    fn from(err: std::num::TryFromIntError) -> ServerError {
+        ServerError::Request(format!("number out of range: {err}"))
+    }
+}
+
+pub type Result<T, E = ServerError> = std::result::Result<T, E>;
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use bytes::Bytes;
+use memmap2::Mmap;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Reading and seeking, as files opened from a [`FileSource`] allow.
+pub trait FileRead: AsyncRead + AsyncSeek + Send {}
+
+impl<T: AsyncRead + AsyncSeek + Send> FileRead for T {}
+
+/// A file opened for reading.
+pub type FileReader = Pin<Box<dyn FileRead>>;
+
+/// What's known about a file before reading it.
+pub struct FileInfo {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Where the files served under `/files` and mounts are read from. Files are
+/// named by the served directory and the request path below it.
+pub trait FileSource: Send + Sync {
+    /// Info on the regular file at `path`, `None` if there is no such file.
+    fn info<'a>(&'a self, dir: &'a str, path: &'a str) -> BoxFuture<'a, Option<FileInfo>>;
+
+    fn open<'a>(&'a self, dir: &'a str, path: &'a str) -> BoxFuture<'a, io::Result<FileReader>>;
+
+    /// Maps the file at `path` into memory, `None` for sources that can't,
+    /// whose files are read instead.
+    fn map<'a>(&'a self, _dir: &'a str, _path: &'a str) -> BoxFuture<'a, Option<io::Result<Mmap>>> {
+        Box::pin(async { None })
+    }
+
+    /// Read-only sources refuse uploads with a 405.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Whether `dir` itself can be served from. Missing files in a
+    /// directory that's gone get a 503 rather than a 404.
+    fn available<'a>(&'a self, _dir: &'a str) -> BoxFuture<'a, bool> {
+        Box::pin(async { true })
+    }
+}
+
+/// Files on disk, the default source.
+pub struct Disk;
+
+impl FileSource for Disk {
+    fn info<'a>(&'a self, dir: &'a str, path: &'a str) -> BoxFuture<'a, Option<FileInfo>> {
+        Box::pin(async move {
+            let metadata = tokio::fs::metadata(resolve_path(dir, path)).await.ok()?;
+            metadata.is_file().then(|| FileInfo {
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+            })
+        })
+    }
+
+    fn open<'a>(&'a self, dir: &'a str, path: &'a str) -> BoxFuture<'a, io::Result<FileReader>> {
+        Box::pin(async move {
+            let file = tokio::fs::File::open(resolve_path(dir, path)).await?;
+            Ok(Box::pin(file) as FileReader)
+        })
+    }
+
+    fn map<'a>(&'a self, dir: &'a str, path: &'a str) -> BoxFuture<'a, Option<io::Result<Mmap>>> {
+        let path = resolve_path(dir, path);
+        Box::pin(async move {
+            let mapped = tokio::task::spawn_blocking(move || {
+                let file = std::fs::File::open(path)?;
+                // SAFETY: the map is only read from. The file shrinking while
+                // it's mapped would fault those reads, which `mmap_min_size`
+                // leaves to whoever turns it on.
+                unsafe { Mmap::map(&file) }
+            });
+            Some(mapped.await.unwrap_or_else(|e| Err(io::Error::other(e))))
+        })
+    }
+
+    fn available<'a>(&'a self, dir: &'a str) -> BoxFuture<'a, bool> {
+        Box::pin(async move {
+            tokio::fs::metadata(dir).await.is_ok_and(|metadata| metadata.is_dir())
+        })
+    }
+}
+
+/// Files held in memory, e.g. embedded with `include_bytes!`. The same
+/// files are found under every served directory, and they can't be
+/// uploaded to.
+pub struct InMemory {
+    files: HashMap<String, Bytes>,
+    /// Reported as every file's modification time, so that validators
+    /// change whenever the process is restarted with new contents.
+    created: Option<SystemTime>,
+}
+
+impl InMemory {
+    pub fn new() -> InMemory {
+        InMemory {
+            files: HashMap::new(),
+            created: Some(SystemTime::now()),
+        }
+    }
+
+    /// Adds a file at `path`, relative to the served directory.
+    pub fn file(mut self, path: &str, contents: impl Into<Bytes>) -> InMemory {
+        self.files.insert(normalize(path), contents.into());
+        self
+    }
+}
+
+impl Default for InMemory {
+    fn default() -> InMemory {
+        InMemory::new()
+    }
+}
+
+impl FileSource for InMemory {
+    fn info<'a>(&'a self, _dir: &'a str, path: &'a str) -> BoxFuture<'a, Option<FileInfo>> {
+        Box::pin(async move {
+            self.files.get(&normalize(path)).map(|contents| FileInfo {
+                len: contents.len() as u64,
+                modified: self.created,
+            })
+        })
+    }
+
+    fn open<'a>(&'a self, _dir: &'a str, path: &'a str) -> BoxFuture<'a, io::Result<FileReader>> {
+        Box::pin(async move {
+            let contents = self.files
+                .get(&normalize(path))
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            Ok(Box::pin(io::Cursor::new(contents.clone())) as FileReader)
+        })
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+/// Another source with a cap on how many of its files are open at once.
+/// Opening waits for one of the open files to be dropped when the cap is
+/// reached.
+pub(crate) struct Limited {
+    inner: Arc<dyn FileSource>,
+    permits: Arc<Semaphore>,
+}
+
+impl Limited {
+    pub(crate) fn new(inner: Arc<dyn FileSource>, permits: Arc<Semaphore>) -> Limited {
+        Limited { inner, permits }
+    }
+}
+
+impl FileSource for Limited {
+    fn info<'a>(&'a self, dir: &'a str, path: &'a str) -> BoxFuture<'a, Option<FileInfo>> {
+        self.inner.info(dir, path)
+    }
+
+    fn open<'a>(&'a self, dir: &'a str, path: &'a str) -> BoxFuture<'a, io::Result<FileReader>> {
+        Box::pin(async move {
+            let permit = self.permits.clone().acquire_owned().await.map_err(io::Error::other)?;
+            let file = self.inner.open(dir, path).await?;
+            Ok(Box::pin(PermittedFile { file, _permit: permit }) as FileReader)
+        })
+    }
+
+    /// A map doesn't keep its file open, so the permit is only held while
+    /// mapping.
+    fn map<'a>(&'a self, dir: &'a str, path: &'a str) -> BoxFuture<'a, Option<io::Result<Mmap>>> {
+        Box::pin(async move {
+            let _permit = match self.permits.acquire().await {
+                Ok(permit) => permit,
+                Err(e) => return Some(Err(io::Error::other(e))),
+            };
+            self.inner.map(dir, path).await
+        })
+    }
+
+    fn read_only(&self) -> bool {
+        self.inner.read_only()
+    }
+
+    fn available<'a>(&'a self, dir: &'a str) -> BoxFuture<'a, bool> {
+        self.inner.available(dir)
+    }
+}
+
+/// An open file holding its permit from [`Limited`] until dropped.
+struct PermittedFile {
+    file: FileReader,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl AsyncRead for PermittedFile {
+    fn poll_read(mut self: Pin<&mut Self>,
+                 cx: &mut Context<'_>,
+                 buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.file.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl AsyncSeek for PermittedFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        self.file.as_mut().start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        self.file.as_mut().poll_complete(cx)
+    }
+}
+
+/// Joins a request path onto the served directory, keeping only its
+/// normal components so that neither a leading `/` nor `.` segments
+/// change where it resolves to.
+pub(crate) fn resolve_path(dir: &str, path: &str) -> PathBuf {
+    let mut resolved = PathBuf::from(dir);
+    for component in Path::new(path).components() {
+        if let Component::Normal(part) = component {
+            resolved.push(part);
+        }
+    }
+    resolved
+}
+
+/// Request path reduced to its normal components, as `InMemory` keys.
+fn normalize(path: &str) -> String {
+    Path::new(path)
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,28 @@
+pub mod basic_http_server;
+pub mod body;
+mod digest;
+pub mod error;
+pub mod file_source;
+pub mod glob;
+pub mod http_date;
+pub mod json;
+mod metrics;
+pub mod mime;
+mod multipart;
+mod path_locks;
+mod rewound;
+pub mod server;
+pub mod server_config;
+mod throttle;
+pub mod upgrade;
+pub mod uri;
+mod websocket;
+
+pub use basic_http_server::{PostHandler, RouteHandler, UpgradeHandler, ACCESS_LOG_TARGET};
+pub use body::Body;
+pub use error::{Result, ServerError};
+pub use file_source::{Disk, FileSource, InMemory};
+pub use http::{Request, Response, StatusCode};
+pub use server::{Server, ServerBuilder};
+pub use server_config::ServerConfig;
+pub use upgrade::Upgraded;
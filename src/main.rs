@@ -1,43 +1,557 @@
+use std::net::IpAddr;
 use std::str::FromStr;
+use std::time::Duration;
 use color_eyre::eyre::Result;
 use tracing_subscriber::{filter::targets::Targets,
                          layer::SubscriberExt,
                          util::SubscriberInitExt};
-use clap::{Command, Arg};
-use basic_http_server::BasicHttpServer;
-
-pub mod basic_http_server;
+use clap::{Command, Arg, ArgAction};
+use tracing::{info, Level};
+use http_server_starter_rust::{Server, ACCESS_LOG_TARGET};
+use http_server_starter_rust::server_config::{EarlyHint, HeaderRule, MethodRule, Mount, RouteTimeout,
+                                               ServerConfig, UpgradeRule, VirtualHost};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
 
-    let filter_layer = Targets::from_str(std::env::var("RUST_LOG")
-                                         .as_deref()
-                                         .unwrap_or("info"))?;
-    let format_layer = tracing_subscriber::fmt::layer()
-        .compact()
-        .with_line_number(true)
-        .with_file(true)
-        .with_target(false);
-    tracing_subscriber::registry()
-        .with(filter_layer)
-        .with(format_layer)
-        .init();
-
     let matches = Command::new("codecrafters-http-server-rust")
         .about("Simple asynchronous HTTP server with Tokio")
         .arg(
             Arg::new("directory")
                 .help("Files directory")
                 .long("directory")
+                .env("HTTP_SERVER_DIR")
                 .value_parser(clap::builder::NonEmptyStringValueParser::new())
                 .default_value("."),
         )
+        .arg(
+            Arg::new("dry-run")
+                .help("Check that the server can start, log its configuration and exit")
+                .long("dry-run")
+                .visible_alias("check")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("log-requests-only")
+                .help("Log a single line per request and nothing else, whatever RUST_LOG says")
+                .long("log-requests-only")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("debug")
+                .help("Serve the effective configuration at /debug/config to loopback clients")
+                .long("debug")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("response-time-header")
+                .help("Report the time taken to handle each request in X-Response-Time")
+                .long("response-time-header")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("debug-headers")
+                .help("Report how many requests each connection has served in X-Connection-Requests")
+                .long("debug-headers")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("title-case-headers")
+                .help("Write response header names in Title-Case rather than lower case")
+                .long("title-case-headers")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verbose-404")
+                .help("Include the request method and path in the body of 404 responses")
+                .long("verbose-404")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dump-bodies")
+                .help("Log the first bytes of each upload's body, at trace level")
+                .long("dump-bodies")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("enable-test-endpoints")
+                .help("Serve /delay/SECS and /status/CODE for testing clients")
+                .long("enable-test-endpoints")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("enable-templates")
+                .help("Fill in {{SERVER_TIME}} and {{REQUEST_PATH}} in served .html files")
+                .long("enable-templates")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("metrics")
+                .help("Serve request and throughput counters at /metrics")
+                .long("metrics")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("teapot")
+                .help("Refuse to brew coffee at /teapot")
+                .long("teapot")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("deny-glob")
+                .help("Refuse /files paths matching this glob, may be repeated")
+                .long("deny-glob")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("serve-hidden")
+                .help("Serve and accept files whose names start with a dot")
+                .long("serve-hidden")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("serve-dotfiles-except")
+                .help("Serve files whose names start with a dot, except those with these names")
+                .long("serve-dotfiles-except")
+                .value_name("NAMES")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .value_delimiter(','),
+        )
+        .arg(
+            Arg::new("cors")
+                .help("Allow cross-origin requests and answer CORS preflight requests")
+                .long("cors")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("cors-allow-methods")
+                .help("Methods CORS preflight requests are told are allowed, rather than the one asked for")
+                .long("cors-allow-methods")
+                .value_name("METHODS")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .value_delimiter(',')
+                .requires("cors"),
+        )
+        .arg(
+            Arg::new("index-names")
+                .help("Files to serve for a directory request, tried in order")
+                .long("index-names")
+                .value_name("NAMES")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .value_delimiter(',')
+                .default_value("index.html"),
+        )
+        .arg(
+            Arg::new("mount")
+                .help("Serve directory ROOT under URL PREFIX, may be repeated")
+                .long("mount")
+                .value_name("PREFIX=ROOT")
+                .value_parser(clap::value_parser!(Mount))
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("vhost")
+                .help("Serve directory DIR under /files to requests for HOST, may be repeated")
+                .long("vhost")
+                .value_name("HOST=DIR")
+                .value_parser(clap::value_parser!(VirtualHost))
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("trust-proxy")
+                .help("Take the client address from forwarding headers set by this proxy, may be repeated")
+                .long("trust-proxy")
+                .value_name("IP")
+                .value_parser(clap::value_parser!(IpAddr))
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("http2")
+                .help("Accept HTTP/2 connections made with prior knowledge (h2c)")
+                .long("http2")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("backlog")
+                .help("Maximum number of connections waiting to be accepted")
+                .long("backlog")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .default_value("1024"),
+        )
+        .arg(
+            Arg::new("no-reuseaddr")
+                .help("Leave SO_REUSEADDR unset on the listening socket")
+                .long("no-reuseaddr")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-tcp-nodelay")
+                .help("Leave Nagle's algorithm enabled on accepted connections")
+                .long("no-tcp-nodelay")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("tcp-keepalive")
+                .help("Enable TCP keepalive, probing after SECS of idle time")
+                .long("tcp-keepalive")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("single-file")
+                .help("Answer every GET with this file and refuse other methods")
+                .long("single-file")
+                .value_name("PATH")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new()),
+        )
+        .arg(
+            Arg::new("root-message")
+                .help("Body of the response to /, to tell instances apart")
+                .long("root-message")
+                .value_name("TEXT"),
+        )
+        .arg(
+            Arg::new("force-download")
+                .help("Send files with Content-Disposition: attachment")
+                .long("force-download")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("max-header-size")
+                .help("Largest request line and headers accepted, in bytes")
+                .long("max-header-size")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("16384"),
+        )
+        .arg(
+            Arg::new("max-request-body-buffer")
+                .help("Largest body a POST to /echo sends back, in bytes")
+                .long("max-request-body-buffer")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("1048576"),
+        )
+        .arg(
+            Arg::new("max-body-size")
+                .help("Largest request body accepted by Content-Length, in bytes")
+                .long("max-body-size")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("1099511627776"),
+        )
+        .arg(
+            Arg::new("header-timeout")
+                .help("Seconds allowed for a request's headers to arrive in full")
+                .long("header-timeout")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("cache-max-age")
+                .help("Let caches keep file responses for SECS")
+                .long("cache-max-age")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("cache-immutable")
+                .help("Mark cacheable file responses immutable")
+                .long("cache-immutable")
+                .requires("cache-max-age")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("header")
+                .help("Add header Name to responses under URL PREFIX, may be repeated")
+                .long("header")
+                .value_name("PREFIX:Name: Value")
+                .value_parser(clap::value_parser!(HeaderRule))
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("allow-methods")
+                .help("Allow only these methods under URL PREFIX, may be repeated")
+                .long("allow-methods")
+                .value_name("PREFIX=METHOD,...")
+                .value_parser(clap::value_parser!(MethodRule))
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("route-timeout")
+                .help("Seconds allowed for handling requests under URL PREFIX, may be repeated")
+                .long("route-timeout")
+                .value_name("PREFIX=SECS")
+                .value_parser(clap::value_parser!(RouteTimeout))
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("upgrade-required")
+                .help("Answer requests under URL PREFIX with 426, asking for PROTOCOL, may be repeated")
+                .long("upgrade-required")
+                .value_name("PREFIX=PROTOCOL")
+                .value_parser(clap::value_parser!(UpgradeRule))
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("early-hint")
+                .help("Send LINK in a 103 Early Hints response to GETs under URL PREFIX, may be repeated")
+                .long("early-hint")
+                .value_name("PREFIX=LINK")
+                .value_parser(clap::value_parser!(EarlyHint))
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("store-content-type")
+                .help("Keep the Content-Type of uploads in FILE.meta and serve files with it")
+                .long("store-content-type")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("empty-upload-204")
+                .help("Answer uploads with an empty body with 204 No Content instead of 201")
+                .long("empty-upload-204")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("create-parents")
+                .help("Create missing directories leading to uploaded files")
+                .long("create-parents")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("file-mode")
+                .help("Octal permissions given to uploaded files, on Unix")
+                .long("file-mode")
+                .value_name("MODE")
+                .value_parser(|mode: &str| {
+                    u32::from_str_radix(mode, 8)
+                        .ok()
+                        .filter(|&mode| mode <= 0o7777)
+                        .ok_or_else(|| format!("invalid octal mode {mode}"))
+                }),
+        )
+        .arg(
+            Arg::new("deflate-raw")
+                .help("Send raw DEFLATE instead of zlib data for the deflate encoding")
+                .long("deflate-raw")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-compression")
+                .help("Never compress responses")
+                .long("no-compression")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("precompressed"),
+        )
+        .arg(
+            Arg::new("precompressed")
+                .help("Serve FILE.br or FILE.gz in place of FILE to clients accepting them")
+                .long("precompressed")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("io-buffer-size")
+                .help("Bytes read at a time from request bodies and streamed files")
+                .long("io-buffer-size")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(u64).range(4096..))
+                .default_value("65536"),
+        )
+        .arg(
+            Arg::new("max-bandwidth")
+                .help("Write responses to each connection at most this many bytes per second")
+                .long("max-bandwidth")
+                .value_name("BYTES_PER_SEC")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("max-open-files")
+                .help("Keep at most N files open at once, queueing further requests")
+                .long("max-open-files")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("mmap")
+                .help("Serve large files from read-only memory maps rather than reading them")
+                .long("mmap")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("mmap-min-size")
+                .help("Smallest file served from a memory map with --mmap")
+                .long("mmap-min-size")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .requires("mmap")
+                .default_value("1048576"),
+        )
+        .arg(
+            Arg::new("max-total-requests")
+                .help("Exit once N requests have been served and open connections are done")
+                .long("max-total-requests")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("follow-symlinks")
+                .help("Serve files reached through symlinks (the default)")
+                .long("follow-symlinks")
+                .action(ArgAction::SetTrue)
+                .overrides_with("no-follow-symlinks"),
+        )
+        .arg(
+            Arg::new("no-follow-symlinks")
+                .help("Refuse file paths that pass through a symlink")
+                .long("no-follow-symlinks")
+                .action(ArgAction::SetTrue)
+                .overrides_with("follow-symlinks"),
+        )
         .get_matches();
-    let dir = matches.get_one::<String>("directory").unwrap();
 
-    let server = BasicHttpServer::new("127.0.0.1:4221", dir).await?;
+    let requests_only = matches.get_flag("log-requests-only");
+    let filter_layer = if requests_only {
+        Targets::new().with_target(ACCESS_LOG_TARGET, Level::INFO)
+    } else {
+        Targets::from_str(std::env::var("RUST_LOG")
+                          .as_deref()
+                          .unwrap_or("info"))?
+    };
+    let format_layer = tracing_subscriber::fmt::layer()
+        .compact()
+        .with_line_number(!requests_only)
+        .with_file(!requests_only)
+        .with_target(false);
+    tracing_subscriber::registry()
+        .with(filter_layer)
+        .with(format_layer)
+        .init();
+
+    let config = ServerConfig {
+        dir: matches.get_one::<String>("directory").unwrap().clone(),
+        debug: matches.get_flag("debug"),
+        response_time_header: matches.get_flag("response-time-header"),
+        debug_headers: matches.get_flag("debug-headers"),
+        test_endpoints: matches.get_flag("enable-test-endpoints"),
+        title_case_headers: matches.get_flag("title-case-headers"),
+        verbose_404: matches.get_flag("verbose-404"),
+        dump_bodies: matches.get_flag("dump-bodies"),
+        metrics: matches.get_flag("metrics"),
+        teapot: matches.get_flag("teapot"),
+        templates: matches.get_flag("enable-templates"),
+        deny_globs: matches
+            .get_many::<String>("deny-glob")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        serve_hidden: matches.get_flag("serve-hidden") || matches.contains_id("serve-dotfiles-except"),
+        denied_dotfiles: matches
+            .get_many::<String>("serve-dotfiles-except")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        cors: matches.get_flag("cors"),
+        cors_allow_methods: matches
+            .get_many::<String>("cors-allow-methods")
+            .unwrap_or_default()
+            .map(|method| method.trim().to_ascii_uppercase())
+            .collect(),
+        index_names: matches
+            .get_many::<String>("index-names")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        mounts: matches
+            .get_many::<Mount>("mount")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        vhosts: matches
+            .get_many::<VirtualHost>("vhost")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        trusted_proxies: matches
+            .get_many::<IpAddr>("trust-proxy")
+            .unwrap_or_default()
+            .copied()
+            .collect(),
+        http2: matches.get_flag("http2"),
+        backlog: *matches.get_one::<u32>("backlog").unwrap(),
+        reuse_address: !matches.get_flag("no-reuseaddr"),
+        tcp_nodelay: !matches.get_flag("no-tcp-nodelay"),
+        tcp_keepalive: matches
+            .get_one::<u64>("tcp-keepalive")
+            .map(|secs| Duration::from_secs(*secs)),
+        single_file: matches.get_one::<String>("single-file").cloned(),
+        root_message: matches.get_one::<String>("root-message").cloned(),
+        force_download: matches.get_flag("force-download"),
+        max_header_size: *matches.get_one::<u64>("max-header-size").unwrap() as usize,
+        max_request_body_buffer: *matches.get_one::<u64>("max-request-body-buffer").unwrap() as usize,
+        max_body_size: *matches.get_one::<u64>("max-body-size").unwrap(),
+        header_timeout: Duration::from_secs(*matches.get_one::<u64>("header-timeout").unwrap()),
+        store_content_type: matches.get_flag("store-content-type"),
+        empty_upload_no_content: matches.get_flag("empty-upload-204"),
+        create_parents: matches.get_flag("create-parents"),
+        file_mode: matches.get_one::<u32>("file-mode").copied(),
+        deflate_raw: matches.get_flag("deflate-raw"),
+        no_compression: matches.get_flag("no-compression"),
+        precompressed: matches.get_flag("precompressed"),
+        io_buffer_size: *matches.get_one::<u64>("io-buffer-size").unwrap() as usize,
+        max_bandwidth: matches.get_one::<u64>("max-bandwidth").copied(),
+        max_open_files: matches
+            .get_one::<u64>("max-open-files")
+            .map(|max| *max as usize),
+        mmap_min_size: matches
+            .get_one::<u64>("mmap-min-size")
+            .copied()
+            .filter(|_| matches.get_flag("mmap")),
+        max_total_requests: matches.get_one::<u64>("max-total-requests").copied(),
+        follow_symlinks: !matches.get_flag("no-follow-symlinks"),
+        cache_max_age: matches
+            .get_one::<u64>("cache-max-age")
+            .map(|secs| Duration::from_secs(*secs)),
+        cache_immutable: matches.get_flag("cache-immutable"),
+        headers: matches
+            .get_many::<HeaderRule>("header")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        method_rules: matches
+            .get_many::<MethodRule>("allow-methods")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        route_timeouts: matches
+            .get_many::<RouteTimeout>("route-timeout")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        upgrade_rules: matches
+            .get_many::<UpgradeRule>("upgrade-required")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        early_hints: matches
+            .get_many::<EarlyHint>("early-hint")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+    };
+
+    let server = Server::builder()
+        .config(config)
+        .build()
+        .await?;
+    if matches.get_flag("dry-run") {
+        info!("configuration OK: {}", server.config().to_json());
+        return Ok(());
+    }
 
     server.run().await?;
     Ok(())
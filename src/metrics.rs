@@ -0,0 +1,61 @@
+use std::sync::Weak;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// How often the throughput gauge is updated.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Weight of the newest sample in the throughput moving average.
+const RATE_SMOOTHING: f64 = 0.2;
+
+/// Counters served at `/metrics`.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    bytes_sent: AtomicU64,
+    /// Bits of the `f64` moving average of bytes sent per second.
+    bytes_per_sec: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn add_bytes_sent(&self, bytes: u64) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn bytes_per_sec(&self) -> f64 {
+        f64::from_bits(self.bytes_per_sec.load(Ordering::Relaxed))
+    }
+
+    /// Updates the throughput gauge from the bytes sent each interval,
+    /// until the metrics are dropped along with the server.
+    pub(crate) async fn sample(metrics: Weak<Metrics>) {
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        let mut last = 0;
+        loop {
+            interval.tick().await;
+            let Some(metrics) = metrics.upgrade() else {
+                return;
+            };
+            let total = metrics.bytes_sent.load(Ordering::Relaxed);
+            let rate = (total - last) as f64 / SAMPLE_INTERVAL.as_secs_f64();
+            last = total;
+            let average = metrics.bytes_per_sec();
+            let average = average + RATE_SMOOTHING * (rate - average);
+            metrics.bytes_per_sec.store(average.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// The Prometheus text format scrape, `requests` being those served.
+    pub(crate) fn render(&self, requests: u64) -> String {
+        let bytes_sent = self.bytes_sent.load(Ordering::Relaxed);
+        format!("# HELP http_requests_total Requests served.\n\
+                 # TYPE http_requests_total counter\n\
+                 http_requests_total {requests}\n\
+                 # HELP http_response_bytes_total Bytes of responses written.\n\
+                 # TYPE http_response_bytes_total counter\n\
+                 http_response_bytes_total {bytes_sent}\n\
+                 # HELP http_response_bytes_per_second Moving average of bytes written per second.\n\
+                 # TYPE http_response_bytes_per_second gauge\n\
+                 http_response_bytes_per_second {:.1}\n",
+                self.bytes_per_sec())
+    }
+}
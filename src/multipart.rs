@@ -0,0 +1,149 @@
+use crate::body::BodyReader;
+use crate::error::{Result, ServerError};
+
+/// Largest header section of a part.
+const MAX_PART_HEADERS: usize = 16384;
+
+/// The boundary of a `multipart/form-data` content type, if that's what
+/// it is.
+pub(crate) fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+/// What the headers of a part say about it.
+pub(crate) struct Part {
+    /// Form field name.
+    pub(crate) name: Option<String>,
+    /// Name of the uploaded file, for file fields.
+    pub(crate) filename: Option<String>,
+}
+
+/// Splits a `multipart/form-data` body into parts as it's read, so that
+/// large files never have to be held in memory.
+pub(crate) struct Multipart<'a> {
+    body: BodyReader<'a>,
+    /// `CRLF--boundary`, which ends every part.
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    /// Inside a part's content, or the preamble before the first part.
+    in_part: bool,
+    done: bool,
+}
+
+impl<'a> Multipart<'a> {
+    pub(crate) fn new(body: BodyReader<'a>, boundary: &str) -> Multipart<'a> {
+        Multipart {
+            body,
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            // Lets the first boundary be found like any other, there being
+            // no line break before it when there's no preamble.
+            buf: b"\r\n".to_vec(),
+            in_part: true,
+            done: false,
+        }
+    }
+
+    /// Moves on to the next part, skipping whatever's left of the current
+    /// one, and returns its headers. `None` once the closing boundary is
+    /// reached, with the rest of the body read.
+    pub(crate) async fn next_part(&mut self) -> Result<Option<Part>> {
+        while self.chunk().await?.is_some() {}
+        if self.done {
+            return Ok(None);
+        }
+
+        self.buf.drain(..self.delimiter.len());
+        self.fill_to(2).await?;
+        if self.buf.starts_with(b"--") {
+            self.done = true;
+            while self.body.chunk().await?.is_some() {}
+            return Ok(None);
+        }
+
+        let end = loop {
+            if let Some(end) = find(&self.buf, b"\r\n\r\n") {
+                break end;
+            }
+            if self.buf.len() > MAX_PART_HEADERS {
+                return Err(ServerError::Request("multipart headers too long".to_string()));
+            }
+            self.fill().await?;
+        };
+        let headers = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+        self.buf.drain(..end + 4);
+        self.in_part = true;
+        Ok(Some(Self::parse_headers(&headers)))
+    }
+
+    /// Next piece of the current part's content, `None` at its end.
+    pub(crate) async fn chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        if !self.in_part {
+            return Ok(None);
+        }
+        loop {
+            if let Some(end) = find(&self.buf, &self.delimiter) {
+                self.in_part = false;
+                let content = self.buf.drain(..end).collect::<Vec<_>>();
+                return Ok(Some(content).filter(|c| !c.is_empty()));
+            }
+            // Whatever can't be the start of a delimiter is content.
+            let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            if safe > 0 {
+                return Ok(Some(self.buf.drain(..safe).collect()));
+            }
+            self.fill().await?;
+        }
+    }
+
+    async fn fill(&mut self) -> Result<()> {
+        match self.body.chunk().await? {
+            Some(chunk) => {
+                self.buf.extend_from_slice(&chunk);
+                Ok(())
+            }
+            None => Err(ServerError::Request("multipart body ends before its closing boundary".to_string())),
+        }
+    }
+
+    async fn fill_to(&mut self, len: usize) -> Result<()> {
+        while self.buf.len() < len {
+            self.fill().await?;
+        }
+        Ok(())
+    }
+
+    /// Picks the field and file names out of a part's header lines.
+    fn parse_headers(headers: &str) -> Part {
+        let mut part = Part { name: None, filename: None };
+        let disposition = headers.split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-disposition"));
+        let Some((_, disposition)) = disposition else {
+            return part;
+        };
+        for param in disposition.split(';').skip(1) {
+            let Some((name, value)) = param.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"').to_string();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "name" => part.name = Some(value),
+                "filename" => part.filename = Some(value),
+                _ => (),
+            }
+        }
+        part
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// A lock per file path, taken by uploads that have to check and write a
+/// file as one step. Uploads to different files never wait on each other,
+/// and a path's lock is dropped once nobody holds or waits for it.
+#[derive(Default)]
+pub(crate) struct PathLocks {
+    locks: std::sync::Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>,
+}
+
+impl PathLocks {
+    /// Waits for the lock on `path`, held until the guard is dropped.
+    pub(crate) async fn lock(&self, path: PathBuf) -> PathGuard<'_> {
+        let lock = self.locks.lock().unwrap().entry(path.clone()).or_default().clone();
+        let guard = lock.lock_owned().await;
+        PathGuard { locks: self, path, _guard: guard }
+    }
+}
+
+pub(crate) struct PathGuard<'a> {
+    locks: &'a PathLocks,
+    path: PathBuf,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl Drop for PathGuard<'_> {
+    fn drop(&mut self) {
+        let mut locks = self.locks.locks.lock().unwrap();
+        // One reference is the map's and one this guard's, any others are
+        // waiting for the lock.
+        if locks.get(&self.path).is_some_and(|lock| Arc::strong_count(lock) == 2) {
+            locks.remove(&self.path);
+        }
+    }
+}
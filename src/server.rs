@@ -0,0 +1,132 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use crate::error::Result;
+use crate::basic_http_server::BasicHttpServer;
+use crate::body::Body;
+use crate::file_source::FileSource;
+use crate::server_config::ServerConfig;
+use crate::upgrade::Upgraded;
+
+/// Address servers are bound to unless told otherwise.
+const DEFAULT_ADDR: &str = "127.0.0.1:4221";
+
+/// An HTTP server ready to accept connections, made with [`Server::builder`].
+///
+/// ```no_run
+/// # async fn serve() -> http_server_starter_rust::Result<()> {
+/// use http_server_starter_rust::Server;
+///
+/// let server = Server::builder()
+///     .directory("/srv/www")
+///     .bind("0.0.0.0:8080")
+///     .build()
+///     .await?;
+/// server.run().await
+/// # }
+/// ```
+pub struct Server {
+    inner: BasicHttpServer,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            addr: DEFAULT_ADDR.to_string(),
+            config: ServerConfig::default(),
+            setup: vec![],
+        }
+    }
+
+    /// Configuration with the served paths resolved.
+    pub fn config(&self) -> &ServerConfig {
+        self.inner.config()
+    }
+
+    /// Address the server is listening on, useful after binding port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// Accepts and serves connections until accepting fails, or until
+    /// [`ServerConfig::max_total_requests`] have been served and the open
+    /// connections are done.
+    pub async fn run(&self) -> Result<()> {
+        self.inner.run().await
+    }
+}
+
+/// Deferred call made on the server once it's been created.
+type Setup = Box<dyn FnOnce(&mut BasicHttpServer)>;
+
+/// Collects a [`Server`]'s options, starting from [`ServerConfig::default`].
+pub struct ServerBuilder {
+    addr: String,
+    config: ServerConfig,
+    setup: Vec<Setup>,
+}
+
+impl ServerBuilder {
+    /// Directory served under `/files`.
+    pub fn directory(mut self, dir: impl Into<String>) -> Self {
+        self.config.dir = dir.into();
+        self
+    }
+
+    /// Address to listen on, `127.0.0.1:4221` by default.
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.addr = addr.into();
+        self
+    }
+
+    /// Replaces all options at once. The directory set so far is replaced
+    /// too, the address, handlers and file source are kept.
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Routes `method` requests under `prefix` to `handler`, see
+    /// [`BasicHttpServer::route`].
+    pub fn route<F, Fut>(mut self, method: &str, prefix: &str, handler: F) -> Self
+    where F: Fn(http::Request<Body>) -> Fut + Send + Sync + 'static,
+          Fut: Future<Output = http::Response<Vec<u8>>> + Send + 'static {
+        let (method, prefix) = (method.to_string(), prefix.to_string());
+        self.setup.push(Box::new(move |server| server.route(&method, &prefix, handler)));
+        self
+    }
+
+    /// Routes POST requests under `prefix` to `handler`, see
+    /// [`BasicHttpServer::post_handler`].
+    pub fn post_handler<F, Fut>(mut self, prefix: &str, handler: F) -> Self
+    where F: Fn(String, Body) -> Fut + Send + Sync + 'static,
+          Fut: Future<Output = http::Response<Vec<u8>>> + Send + 'static {
+        let prefix = prefix.to_string();
+        self.setup.push(Box::new(move |server| server.post_handler(&prefix, handler)));
+        self
+    }
+
+    /// Hands connections switching to `protocol` to `handler`, see
+    /// [`BasicHttpServer::upgrade_handler`].
+    pub fn upgrade_handler<F, Fut>(mut self, protocol: &str, handler: F) -> Self
+    where F: Fn(String, Upgraded) -> Fut + Send + Sync + 'static,
+          Fut: Future<Output = ()> + Send + 'static {
+        let protocol = protocol.to_string();
+        self.setup.push(Box::new(move |server| server.upgrade_handler(&protocol, handler)));
+        self
+    }
+
+    /// Reads served files from `source` rather than the disk.
+    pub fn file_source(mut self, source: impl FileSource + 'static) -> Self {
+        self.setup.push(Box::new(move |server| server.file_source(source)));
+        self
+    }
+
+    /// Resolves the served directories and binds the listening socket.
+    pub async fn build(self) -> Result<Server> {
+        let mut inner = BasicHttpServer::new(&self.addr, self.config).await?;
+        for setup in self.setup {
+            setup(&mut inner);
+        }
+        Ok(Server { inner })
+    }
+}
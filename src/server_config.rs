@@ -0,0 +1,773 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use crate::{glob, json};
+
+/// Options the server is started with, shared by all connection handlers.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Directory served under `/files`.
+    pub dir: String,
+    /// Enables the loopback-only `/debug/config` endpoint.
+    pub debug: bool,
+    /// Adds `X-Response-Time`, the time taken to handle the request up to
+    /// sending the response, to every HTTP/1 response.
+    pub response_time_header: bool,
+    /// Adds `X-Connection-Requests`, the number of requests served on the
+    /// connection so far this one included, to every HTTP/1 response.
+    pub debug_headers: bool,
+    /// Writes header names in Title-Case, like `Content-Length`, rather
+    /// than in lower case, for clients that wrongly care.
+    pub title_case_headers: bool,
+    /// Names the method and path of the request in the body of plain text
+    /// 404 responses, to help track down misrouted requests.
+    pub verbose_404: bool,
+    /// Enables endpoints for testing clients, `/delay/<secs>` (up to a
+    /// minute) and `/status/<code>`.
+    pub test_endpoints: bool,
+    /// Logs the start of each POST and PUT body at trace level.
+    pub dump_bodies: bool,
+    /// Serves request and throughput counters at `/metrics`, in the
+    /// Prometheus text format.
+    pub metrics: bool,
+    /// Answers `/teapot` with 418 I'm a teapot.
+    pub teapot: bool,
+    /// Fills in `{{SERVER_TIME}}` and `{{REQUEST_PATH}}` in served `.html`
+    /// files.
+    pub templates: bool,
+    /// Globs of `/files` paths that are never served or written. Patterns
+    /// without a `/` match any single path component, others match the
+    /// whole path relative to `dir`.
+    pub deny_globs: Vec<String>,
+    /// Whether files and directories whose names start with `.` are served
+    /// and written, rather than refused like missing ones.
+    pub serve_hidden: bool,
+    /// Hidden files and directories refused by name even when the others
+    /// are served, like `.git` or `.env`.
+    pub denied_dotfiles: Vec<String>,
+    /// Allows cross-origin requests from any origin, answering CORS
+    /// preflight requests.
+    pub cors: bool,
+    /// Methods listed in `Access-Control-Allow-Methods` on preflight
+    /// responses. When empty, the method asked for is allowed if the server
+    /// handles it on the path.
+    pub cors_allow_methods: Vec<String>,
+    /// Files served for a directory request, the first one found wins.
+    pub index_names: Vec<String>,
+    /// Additional directories served under their own URL prefixes,
+    /// alongside `dir` under `/files`.
+    pub mounts: Vec<Mount>,
+    /// Directories served under `/files` in place of `dir` for requests
+    /// to particular hosts.
+    pub vhosts: Vec<VirtualHost>,
+    /// Proxies whose `Forwarded` and `X-Forwarded-For` headers are taken
+    /// to name the client, which is logged and checked in its place.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Accepts HTTP/2 connections started with prior knowledge (h2c).
+    pub http2: bool,
+    /// Maximum number of connections waiting to be accepted.
+    pub backlog: u32,
+    /// Sets `SO_REUSEADDR` on the listening socket, so that a restarted
+    /// server can bind while old connections are in `TIME_WAIT`.
+    pub reuse_address: bool,
+    /// Sets `TCP_NODELAY` on accepted connections.
+    pub tcp_nodelay: bool,
+    /// Idle time before TCP keepalive probes start, keepalive is left off
+    /// when unset.
+    pub tcp_keepalive: Option<Duration>,
+    /// When set, every GET is answered with this file and every other
+    /// method is refused.
+    pub single_file: Option<String>,
+    /// Body of the response to `/`, which is empty when unset.
+    pub root_message: Option<String>,
+    /// Marks every file response as an attachment, as `?download=1`
+    /// does for a single request.
+    pub force_download: bool,
+    /// Keeps the `Content-Type` of uploads in a `.meta` file next to them,
+    /// to be sent back when they're served.
+    pub store_content_type: bool,
+    /// Answers an upload with an empty body with 204 rather than 201.
+    pub empty_upload_no_content: bool,
+    /// Creates the missing directories leading to an uploaded file, rather
+    /// than failing the upload.
+    pub create_parents: bool,
+    /// Permission bits given to uploaded files on Unix, rather than those
+    /// the umask leaves.
+    pub file_mode: Option<u32>,
+    /// Largest request line and header section accepted, counted up to
+    /// the blank line ending them. Larger ones get a 431.
+    pub max_header_size: usize,
+    /// Largest body a POST to `/echo` sends back. It's held in memory
+    /// whole, unlike uploads, and longer ones get a 413.
+    pub max_request_body_buffer: usize,
+    /// Largest body a request may announce with `Content-Length`. Larger
+    /// ones get a 413 before any of the body is read.
+    pub max_body_size: u64,
+    /// Time allowed for a request's line and headers to arrive in full,
+    /// after which the client gets a 408 and the connection is closed.
+    pub header_timeout: Duration,
+    /// Sends bare DEFLATE data rather than zlib for `deflate`, for clients
+    /// that predate the spec being settled.
+    pub deflate_raw: bool,
+    /// Never compresses responses, whatever the client accepts.
+    pub no_compression: bool,
+    /// Serves `file.br` or `file.gz`, when present next to a requested
+    /// file, to clients accepting that encoding.
+    pub precompressed: bool,
+    /// Bytes read at a time from request bodies and from files streamed
+    /// into responses.
+    pub io_buffer_size: usize,
+    /// Bytes per second each connection's responses are written at most.
+    pub max_bandwidth: Option<u64>,
+    /// Cap on files open at once for serving and uploads, further requests
+    /// wait for one to be closed.
+    pub max_open_files: Option<usize>,
+    /// Files at least this large are written out from a read-only memory
+    /// map rather than read into memory, when set. Files mustn't be
+    /// truncated while they're served this way.
+    pub mmap_min_size: Option<u64>,
+    /// Requests served before the server stops accepting connections and
+    /// returns from `run` once the open ones are done, so a supervisor can
+    /// start it afresh.
+    pub max_total_requests: Option<u64>,
+    /// Whether file paths may pass through symlinks, possibly leading out
+    /// of the served directory.
+    pub follow_symlinks: bool,
+    /// How long caches may keep file responses, no caching headers are
+    /// sent when unset.
+    pub cache_max_age: Option<Duration>,
+    /// Marks cacheable file responses `immutable` as well.
+    pub cache_immutable: bool,
+    /// Headers added to the responses to requests under a path prefix.
+    pub headers: Vec<HeaderRule>,
+    /// Methods allowed under a path prefix, others get a 405. The longest
+    /// matching prefix applies, and paths under none allow every method.
+    pub method_rules: Vec<MethodRule>,
+    /// Time allowed for handling requests under a path prefix, from their
+    /// headers being read until the response is ready. The longest
+    /// matching prefix applies, and paths under none have no limit.
+    pub route_timeouts: Vec<RouteTimeout>,
+    /// Protocols requests under a path prefix must be made with, others
+    /// get a 426 naming the protocol to switch to.
+    pub upgrade_rules: Vec<UpgradeRule>,
+    /// `Link` headers sent in a 103 Early Hints response ahead of the
+    /// response to GETs under a path prefix.
+    pub early_hints: Vec<EarlyHint>,
+}
+
+impl Default for ServerConfig {
+    /// The defaults of the command line options.
+    fn default() -> ServerConfig {
+        ServerConfig {
+            dir: ".".to_string(),
+            debug: false,
+            response_time_header: false,
+            debug_headers: false,
+            title_case_headers: false,
+            verbose_404: false,
+            test_endpoints: false,
+            dump_bodies: false,
+            metrics: false,
+            teapot: false,
+            templates: false,
+            deny_globs: vec![],
+            serve_hidden: false,
+            denied_dotfiles: vec![],
+            cors: false,
+            cors_allow_methods: vec![],
+            index_names: vec!["index.html".to_string()],
+            mounts: vec![],
+            vhosts: vec![],
+            trusted_proxies: vec![],
+            http2: false,
+            backlog: 1024,
+            reuse_address: true,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            single_file: None,
+            root_message: None,
+            force_download: false,
+            store_content_type: false,
+            empty_upload_no_content: false,
+            create_parents: false,
+            file_mode: None,
+            max_header_size: 16384,
+            max_request_body_buffer: 1 << 20,
+            max_body_size: 1 << 40,
+            header_timeout: Duration::from_secs(10),
+            deflate_raw: false,
+            no_compression: false,
+            precompressed: false,
+            io_buffer_size: 65536,
+            max_bandwidth: None,
+            max_open_files: None,
+            mmap_min_size: None,
+            max_total_requests: None,
+            follow_symlinks: true,
+            cache_max_age: None,
+            cache_immutable: false,
+            headers: vec![],
+            method_rules: vec![],
+            route_timeouts: vec![],
+            upgrade_rules: vec![],
+            early_hints: vec![],
+        }
+    }
+}
+
+/// A directory served under a URL path prefix.
+#[derive(Debug, Clone)]
+pub struct Mount {
+    pub prefix: String,
+    pub root: String,
+}
+
+impl FromStr for Mount {
+    type Err = String;
+
+    /// Parses the `PREFIX=ROOT` form taken by `--mount`.
+    fn from_str(s: &str) -> Result<Mount, String> {
+        let (prefix, root) = s.split_once('=')
+            .ok_or_else(|| format!("expected PREFIX=ROOT, got {s}"))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("mount prefix {prefix} must start with '/'"));
+        }
+        if root.is_empty() {
+            return Err(format!("missing root directory for mount prefix {prefix}"));
+        }
+
+        Ok(Mount {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            root: root.to_string(),
+        })
+    }
+}
+
+/// A header set on every response to a request under a path prefix.
+#[derive(Debug, Clone)]
+pub struct HeaderRule {
+    pub prefix: String,
+    pub name: http::HeaderName,
+    pub value: http::HeaderValue,
+}
+
+impl FromStr for HeaderRule {
+    type Err = String;
+
+    /// Parses the `PREFIX:Name: Value` form taken by `--header`.
+    fn from_str(s: &str) -> Result<HeaderRule, String> {
+        let (prefix, header) = s.split_once(':')
+            .ok_or_else(|| format!("expected PREFIX:Name: Value, got {s}"))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("header prefix {prefix} must start with '/'"));
+        }
+        let (name, value) = header.split_once(':')
+            .ok_or_else(|| format!("expected Name: Value after the prefix, got {header}"))?;
+        let name = http::HeaderName::from_str(name.trim())
+            .map_err(|_| format!("invalid header name {name}"))?;
+        let value = http::HeaderValue::from_str(value.trim())
+            .map_err(|_| format!("invalid value for header {name}"))?;
+
+        Ok(HeaderRule {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            name,
+            value,
+        })
+    }
+}
+
+/// Methods allowed for requests under a path prefix.
+#[derive(Debug, Clone)]
+pub struct MethodRule {
+    pub prefix: String,
+    /// Upper case method names.
+    pub methods: Vec<String>,
+}
+
+impl FromStr for MethodRule {
+    type Err = String;
+
+    /// Parses the `PREFIX=METHOD,...` form taken by `--allow-methods`.
+    fn from_str(s: &str) -> Result<MethodRule, String> {
+        let (prefix, methods) = s.split_once('=')
+            .ok_or_else(|| format!("expected PREFIX=METHOD,..., got {s}"))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("method rule prefix {prefix} must start with '/'"));
+        }
+        let methods = methods.split(',')
+            .map(|m| m.trim().to_ascii_uppercase())
+            .filter(|m| !m.is_empty())
+            .collect::<Vec<_>>();
+        if methods.is_empty() {
+            return Err(format!("no methods given for prefix {prefix}"));
+        }
+
+        Ok(MethodRule {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            methods,
+        })
+    }
+}
+
+/// Time allowed for handling requests under a path prefix.
+#[derive(Debug, Clone)]
+pub struct RouteTimeout {
+    pub prefix: String,
+    pub timeout: Duration,
+}
+
+impl FromStr for RouteTimeout {
+    type Err = String;
+
+    /// Parses the `PREFIX=SECS` form taken by `--route-timeout`.
+    fn from_str(s: &str) -> Result<RouteTimeout, String> {
+        let (prefix, secs) = s.split_once('=')
+            .ok_or_else(|| format!("expected PREFIX=SECS, got {s}"))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("route timeout prefix {prefix} must start with '/'"));
+        }
+        let secs = secs.trim().parse::<u64>()
+            .ok()
+            .filter(|&secs| secs > 0)
+            .ok_or_else(|| format!("invalid timeout {secs} for prefix {prefix}"))?;
+
+        Ok(RouteTimeout {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            timeout: Duration::from_secs(secs),
+        })
+    }
+}
+
+/// Protocol requests under a path prefix must switch to.
+#[derive(Debug, Clone)]
+pub struct UpgradeRule {
+    pub prefix: String,
+    /// As sent in the `Upgrade` header, like `TLS/1.2` or `h2c`.
+    pub protocol: String,
+}
+
+impl FromStr for UpgradeRule {
+    type Err = String;
+
+    /// Parses the `PREFIX=PROTOCOL` form taken by `--upgrade-required`.
+    fn from_str(s: &str) -> Result<UpgradeRule, String> {
+        let (prefix, protocol) = s.split_once('=')
+            .ok_or_else(|| format!("expected PREFIX=PROTOCOL, got {s}"))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("upgrade rule prefix {prefix} must start with '/'"));
+        }
+        let protocol = protocol.trim();
+        if http::HeaderValue::from_str(protocol).is_err() || protocol.is_empty() {
+            return Err(format!("invalid protocol {protocol} for prefix {prefix}"));
+        }
+
+        Ok(UpgradeRule {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            protocol: protocol.to_string(),
+        })
+    }
+}
+
+/// A `Link` hinted at early for requests under a path prefix.
+#[derive(Debug, Clone)]
+pub struct EarlyHint {
+    pub prefix: String,
+    /// As sent in the `Link` header, like `</style.css>; rel=preload; as=style`.
+    pub link: String,
+}
+
+impl FromStr for EarlyHint {
+    type Err = String;
+
+    /// Parses the `PREFIX=LINK` form taken by `--early-hint`.
+    fn from_str(s: &str) -> Result<EarlyHint, String> {
+        let (prefix, link) = s.split_once('=')
+            .ok_or_else(|| format!("expected PREFIX=LINK, got {s}"))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("early hint prefix {prefix} must start with '/'"));
+        }
+        let link = link.trim();
+        if http::HeaderValue::from_str(link).is_err() || link.is_empty() {
+            return Err(format!("invalid link {link} for prefix {prefix}"));
+        }
+
+        Ok(EarlyHint {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            link: link.to_string(),
+        })
+    }
+}
+
+/// A directory served under `/files` for requests naming a given host.
+#[derive(Debug, Clone)]
+pub struct VirtualHost {
+    pub host: String,
+    pub dir: String,
+}
+
+impl FromStr for VirtualHost {
+    type Err = String;
+
+    /// Parses the `HOST=DIR` form taken by `--vhost`.
+    fn from_str(s: &str) -> Result<VirtualHost, String> {
+        let (host, dir) = s.split_once('=')
+            .ok_or_else(|| format!("expected HOST=DIR, got {s}"))?;
+        if host.is_empty() {
+            return Err(format!("missing host name for virtual host directory {dir}"));
+        }
+        if dir.is_empty() {
+            return Err(format!("missing directory for virtual host {host}"));
+        }
+
+        Ok(VirtualHost {
+            host: host.to_ascii_lowercase(),
+            dir: dir.to_string(),
+        })
+    }
+}
+
+impl ServerConfig {
+    /// Effective configuration as reported by `/debug/config`.
+    pub fn to_json(&self) -> String {
+        let mounts = self.mounts.iter().map(|m| {
+            json::object(&[("prefix", json::string(&m.prefix)),
+                           ("root", json::string(&m.root))])
+        });
+        let vhosts = self.vhosts.iter().map(|v| {
+            json::object(&[("host", json::string(&v.host)),
+                           ("directory", json::string(&v.dir))])
+        });
+        let headers = self.headers.iter().map(|h| {
+            json::object(&[("prefix", json::string(&h.prefix)),
+                           ("name", json::string(h.name.as_str())),
+                           ("value", json::string(h.value.to_str().unwrap_or_default()))])
+        });
+        let method_rules = self.method_rules.iter().map(|r| {
+            json::object(&[("prefix", json::string(&r.prefix)),
+                           ("methods", json::array(r.methods.iter().map(|m| json::string(m))))])
+        });
+        let route_timeouts = self.route_timeouts.iter().map(|t| {
+            json::object(&[("prefix", json::string(&t.prefix)),
+                           ("timeout_secs", t.timeout.as_secs().to_string())])
+        });
+        let upgrade_rules = self.upgrade_rules.iter().map(|r| {
+            json::object(&[("prefix", json::string(&r.prefix)),
+                           ("protocol", json::string(&r.protocol))])
+        });
+        let early_hints = self.early_hints.iter().map(|h| {
+            json::object(&[("prefix", json::string(&h.prefix)),
+                           ("link", json::string(&h.link))])
+        });
+        json::object(&[
+            ("directory", json::string(&self.dir)),
+            ("debug", self.debug.to_string()),
+            ("response_time_header", self.response_time_header.to_string()),
+            ("debug_headers", self.debug_headers.to_string()),
+            ("title_case_headers", self.title_case_headers.to_string()),
+            ("verbose_404", self.verbose_404.to_string()),
+            ("test_endpoints", self.test_endpoints.to_string()),
+            ("dump_bodies", self.dump_bodies.to_string()),
+            ("metrics", self.metrics.to_string()),
+            ("teapot", self.teapot.to_string()),
+            ("templates", self.templates.to_string()),
+            ("deny_globs", json::array(self.deny_globs.iter().map(|g| json::string(g)))),
+            ("serve_hidden", self.serve_hidden.to_string()),
+            ("denied_dotfiles", json::array(self.denied_dotfiles.iter().map(|n| json::string(n)))),
+            ("cors", self.cors.to_string()),
+            ("cors_allow_methods", json::array(self.cors_allow_methods.iter().map(|m| json::string(m)))),
+            ("index_names", json::array(self.index_names.iter().map(|n| json::string(n)))),
+            ("mounts", json::array(mounts)),
+            ("vhosts", json::array(vhosts)),
+            ("trusted_proxies", json::array(self.trusted_proxies.iter()
+                                            .map(|ip| json::string(&ip.to_string())))),
+            ("http2", self.http2.to_string()),
+            ("backlog", self.backlog.to_string()),
+            ("reuse_address", self.reuse_address.to_string()),
+            ("tcp_nodelay", self.tcp_nodelay.to_string()),
+            ("tcp_keepalive_secs", self.tcp_keepalive
+             .map_or("null".to_string(), |d| d.as_secs().to_string())),
+            ("single_file", self.single_file
+             .as_deref()
+             .map_or("null".to_string(), json::string)),
+            ("root_message", self.root_message.as_deref().map_or("null".to_string(), json::string)),
+            ("force_download", self.force_download.to_string()),
+            ("store_content_type", self.store_content_type.to_string()),
+            ("empty_upload_no_content", self.empty_upload_no_content.to_string()),
+            ("create_parents", self.create_parents.to_string()),
+            ("file_mode", self.file_mode.map_or("null".to_string(), |m| json::string(&format!("{m:04o}")))),
+            ("max_header_size", self.max_header_size.to_string()),
+            ("max_request_body_buffer", self.max_request_body_buffer.to_string()),
+            ("max_body_size", self.max_body_size.to_string()),
+            ("header_timeout_secs", self.header_timeout.as_secs().to_string()),
+            ("deflate_raw", self.deflate_raw.to_string()),
+            ("no_compression", self.no_compression.to_string()),
+            ("precompressed", self.precompressed.to_string()),
+            ("io_buffer_size", self.io_buffer_size.to_string()),
+            ("max_bandwidth", self.max_bandwidth
+             .map_or("null".to_string(), |n| n.to_string())),
+            ("max_open_files", self.max_open_files
+             .map_or("null".to_string(), |n| n.to_string())),
+            ("mmap_min_size", self.mmap_min_size
+             .map_or("null".to_string(), |n| n.to_string())),
+            ("max_total_requests", self.max_total_requests
+             .map_or("null".to_string(), |n| n.to_string())),
+            ("follow_symlinks", self.follow_symlinks.to_string()),
+            ("cache_max_age_secs", self.cache_max_age
+             .map_or("null".to_string(), |d| d.as_secs().to_string())),
+            ("cache_immutable", self.cache_immutable.to_string()),
+            ("headers", json::array(headers)),
+            ("method_rules", json::array(method_rules)),
+            ("route_timeouts", json::array(route_timeouts)),
+            ("upgrade_rules", json::array(upgrade_rules)),
+            ("early_hints", json::array(early_hints)),
+        ])
+    }
+
+    /// Finds the directory serving `path`, returning it along with the
+    /// remainder of the path below the mount prefix. The longest matching
+    /// prefix wins, and `--mount` entries win over `/files`, which maps to
+    /// the directory of `host`.
+    pub fn resolve_mount<'a>(&'a self, path: &'a str, host: Option<&str>)
+                             -> Option<(&'a str, &'a str)> {
+        std::iter::once(("/files", self.host_dir(host)))
+            .chain(self.mounts.iter().map(|m| (m.prefix.as_str(), m.root.as_str())))
+            .filter_map(|(prefix, root)| {
+                strip_path_prefix(path, prefix).map(|rest| (prefix.len(), root, rest))
+            })
+            .max_by_key(|(prefix_len, ..)| *prefix_len)
+            .map(|(_, root, rest)| (root, rest))
+    }
+
+    /// Directory served under `/files` for requests to `host`, given as in
+    /// the Host header. Unknown or missing hosts get `dir`.
+    pub fn host_dir(&self, host: Option<&str>) -> &str {
+        let Some(host) = host else {
+            return &self.dir;
+        };
+        // Drop the port, taking care not to cut into a bracketed IPv6 address.
+        let name = host
+            .rsplit_once(':')
+            .filter(|(_, port)| port.bytes().all(|b| b.is_ascii_digit()))
+            .map_or(host, |(name, _)| name);
+
+        self.vhosts
+            .iter()
+            .find(|v| v.host.eq_ignore_ascii_case(name))
+            .map_or(&self.dir, |v| &v.dir)
+    }
+
+    /// Human-readable list of the optional behaviours that are switched on.
+    pub fn enabled_features(&self) -> Vec<String> {
+        let mut features = vec![];
+        if !self.no_compression {
+            features.push("gzip streaming".to_string());
+        }
+        if self.http2 {
+            features.push("h2c".to_string());
+        }
+        if let Some(file) = &self.single_file {
+            features.push(format!("single file {file}"));
+        }
+        if self.force_download {
+            features.push("forced downloads".to_string());
+        }
+        if self.store_content_type {
+            features.push("stored content types".to_string());
+        }
+        if self.create_parents {
+            features.push("parent directory creation".to_string());
+        }
+        if let Some(mode) = self.file_mode {
+            features.push(format!("upload mode {mode:04o}"));
+        }
+        if self.cors && !self.cors_allow_methods.is_empty() {
+            features.push(format!("CORS allowing {}", self.cors_allow_methods.join(",")));
+        } else if self.cors {
+            features.push("CORS".to_string());
+        }
+        if self.serve_hidden && !self.denied_dotfiles.is_empty() {
+            features.push(format!("hidden files except {}", self.denied_dotfiles.join(",")));
+        } else if self.serve_hidden {
+            features.push("hidden files".to_string());
+        }
+        if self.deflate_raw {
+            features.push("raw deflate".to_string());
+        }
+        if self.no_compression {
+            features.push("no compression".to_string());
+        }
+        if self.precompressed {
+            features.push("precompressed files".to_string());
+        }
+        if let Some(rate) = self.max_bandwidth {
+            features.push(format!("at most {rate} bytes/s per connection"));
+        }
+        if let Some(max) = self.max_open_files {
+            features.push(format!("at most {max} open files"));
+        }
+        if let Some(min) = self.mmap_min_size {
+            features.push(format!("mmap from {min} bytes"));
+        }
+        if let Some(max) = self.max_total_requests {
+            features.push(format!("exit after {max} requests"));
+        }
+        if !self.follow_symlinks {
+            features.push("symlinks not followed".to_string());
+        }
+        if let Some(idle) = self.tcp_keepalive {
+            features.push(format!("TCP keepalive ({}s)", idle.as_secs()));
+        }
+        if self.debug {
+            features.push("debug endpoint".to_string());
+        }
+        if self.test_endpoints {
+            features.push("test endpoints".to_string());
+        }
+        if self.debug_headers {
+            features.push("debug headers".to_string());
+        }
+        if self.title_case_headers {
+            features.push("Title-Case headers".to_string());
+        }
+        if self.verbose_404 {
+            features.push("verbose 404s".to_string());
+        }
+        if self.dump_bodies {
+            features.push("body dumps".to_string());
+        }
+        if self.metrics {
+            features.push("metrics".to_string());
+        }
+        if self.teapot {
+            features.push("teapot".to_string());
+        }
+        if self.templates {
+            features.push("HTML templates".to_string());
+        }
+        if !self.deny_globs.is_empty() {
+            features.push(format!("deny globs ({})", self.deny_globs.len()));
+        }
+        for mount in &self.mounts {
+            features.push(format!("mount {} -> {}", mount.prefix, mount.root));
+        }
+        if let Some(max_age) = self.cache_max_age {
+            let immutable = if self.cache_immutable { ", immutable" } else { "" };
+            features.push(format!("file caching ({}s{immutable})", max_age.as_secs()));
+        }
+        if !self.headers.is_empty() {
+            features.push(format!("route headers ({})", self.headers.len()));
+        }
+        if !self.method_rules.is_empty() {
+            features.push(format!("method rules ({})", self.method_rules.len()));
+        }
+        if !self.route_timeouts.is_empty() {
+            features.push(format!("route timeouts ({})", self.route_timeouts.len()));
+        }
+        if !self.upgrade_rules.is_empty() {
+            features.push(format!("upgrade rules ({})", self.upgrade_rules.len()));
+        }
+        if !self.early_hints.is_empty() {
+            features.push(format!("early hints ({})", self.early_hints.len()));
+        }
+        if !self.trusted_proxies.is_empty() {
+            features.push(format!("trusted proxies ({})", self.trusted_proxies.len()));
+        }
+        for vhost in &self.vhosts {
+            features.push(format!("vhost {} -> {}", vhost.host, vhost.dir));
+        }
+        features
+    }
+
+    /// The time allowed for handling a request for `path`, if limited.
+    pub fn route_timeout(&self, path: &str) -> Option<Duration> {
+        self.route_timeouts
+            .iter()
+            .filter(|t| strip_path_prefix(path, &t.prefix).is_some())
+            .max_by_key(|t| t.prefix.len())
+            .map(|t| t.timeout)
+    }
+
+    /// The protocol requests for `path` must switch to, if any. The
+    /// longest matching prefix applies.
+    pub fn upgrade_required(&self, path: &str) -> Option<&str> {
+        self.upgrade_rules
+            .iter()
+            .filter(|r| strip_path_prefix(path, &r.prefix).is_some())
+            .max_by_key(|r| r.prefix.len())
+            .map(|r| r.protocol.as_str())
+    }
+
+    /// `Link` values to hint at before answering a GET for `path`, from
+    /// every matching prefix.
+    pub fn early_hints(&self, path: &str) -> Vec<&str> {
+        self.early_hints
+            .iter()
+            .filter(|h| strip_path_prefix(path, &h.prefix).is_some())
+            .map(|h| h.link.as_str())
+            .collect()
+    }
+
+    /// Whether the method rules let `method` be used on `path`. HEAD goes
+    /// wherever GET does.
+    pub fn method_allowed(&self, path: &str, method: &str) -> bool {
+        let rule = self.method_rules
+            .iter()
+            .filter(|rule| strip_path_prefix(path, &rule.prefix).is_some())
+            .max_by_key(|rule| rule.prefix.len());
+        rule.is_none_or(|rule| {
+            rule.methods.iter().any(|m| m == method || (m == "GET" && method == "HEAD"))
+        })
+    }
+
+    /// Whether `path` passes through a hidden file or directory that isn't
+    /// to be served.
+    pub fn is_hidden(&self, path: &str) -> bool {
+        path.split('/').any(|c| {
+            c.starts_with('.') && c != "."
+                && (!self.serve_hidden || self.denied_dotfiles.iter().any(|name| name == c))
+        })
+    }
+
+    /// Whether `path`, relative to `dir`, matches one of `deny_globs`.
+    pub fn is_denied(&self, path: &str) -> bool {
+        let components: Vec<&str> = path
+            .split('/')
+            .filter(|c| !c.is_empty() && *c != ".")
+            .collect();
+        let relative = components.join("/");
+
+        self.deny_globs.iter().any(|pattern| {
+            if pattern.contains('/') {
+                glob::matches(pattern.trim_start_matches('/'), &relative)
+            } else {
+                components.iter().any(|c| glob::matches(pattern, c))
+            }
+        })
+    }
+}
+
+/// Strips `prefix` off `path`, ignoring case, if it ends on a path
+/// segment boundary.
+pub(crate) fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path
+        .get(..prefix.len())
+        .filter(|p| p.eq_ignore_ascii_case(prefix))
+        .map(|_| &path[prefix.len()..])?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gzip_streaming_is_only_listed_with_compression() {
+        let features = ServerConfig::default().enabled_features();
+        assert!(features.iter().any(|f| f == "gzip streaming"));
+
+        let config = ServerConfig { no_compression: true, ..ServerConfig::default() };
+        let features = config.enabled_features();
+        assert!(!features.iter().any(|f| f == "gzip streaming"));
+        assert!(features.iter().any(|f| f == "no compression"));
+    }
+}
@@ -0,0 +1,56 @@
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+/// Token bucket limiting the bytes per second written to a connection.
+pub(crate) struct Throttle {
+    rate: f64,
+    /// Most bytes sent at once, a tenth of a second's worth.
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Throttle {
+    pub(crate) fn new(bytes_per_sec: u64) -> Throttle {
+        let rate = bytes_per_sec as f64;
+        let burst = (rate / 10.0).max(1.0);
+        Throttle { rate, burst, tokens: burst, last: Instant::now() }
+    }
+
+    /// Waits until some of `want` bytes may be sent, returning how many.
+    async fn take(&mut self, want: usize) -> usize {
+        let want = (want as f64).min(self.burst);
+        self.refill();
+        if self.tokens < want {
+            tokio::time::sleep(Duration::from_secs_f64((want - self.tokens) / self.rate)).await;
+            self.refill();
+        }
+        let n = self.tokens.min(want).floor().max(1.0);
+        self.tokens -= n;
+        n as usize
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+}
+
+/// Writes all of `data`, no faster than `throttle` allows if there is one.
+pub(crate) async fn write_all<W>(io: &mut W, mut data: &[u8], throttle: Option<&mut Throttle>)
+                                 -> io::Result<()>
+where W: AsyncWrite + Unpin {
+    let Some(throttle) = throttle else {
+        return io.write_all(data).await;
+    };
+    while !data.is_empty() {
+        let n = throttle.take(data.len()).await;
+        io.write_all(&data[..n]).await?;
+        data = &data[n..];
+    }
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::*;
+use crate::digest::{base64, sha1};
+use crate::error::{Result, ServerError};
+
+/// Appended to the client's key before hashing, per RFC 6455.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Frames with larger payloads are refused, closing the connection.
+const MAX_PAYLOAD_LEN: u64 = 16 << 20;
+
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Close status sent for a frame whose payload is too large.
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// The `Sec-WebSocket-Accept` value answering a `Sec-WebSocket-Key`.
+pub(crate) fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{ACCEPT_GUID}", key.trim()).as_bytes()))
+}
+
+/// Sends every data frame the client sends straight back, answers pings,
+/// and returns once either side closes the connection.
+pub(crate) async fn echo<T>(mut io: T) -> Result<()>
+where T: AsyncRead + AsyncWrite + Unpin {
+    loop {
+        let mut head = [0; 2];
+        io.read_exact(&mut head).await?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        let masked = head[1] & 0x80 != 0;
+        let len = match head[1] & 0x7f {
+            126 => io.read_u16().await? as u64,
+            127 => io.read_u64().await?,
+            len => len as u64,
+        };
+        if !masked {
+            return Err(ServerError::Request("unmasked WebSocket frame from client".to_string()));
+        }
+        if len > MAX_PAYLOAD_LEN {
+            info!("WebSocket frame of {len} bytes too large");
+            write_frame(&mut io, true, OP_CLOSE, &CLOSE_TOO_BIG.to_be_bytes()).await?;
+            return Ok(());
+        }
+
+        let mut mask = [0; 4];
+        io.read_exact(&mut mask).await?;
+        let mut payload = vec![0; len as usize];
+        io.read_exact(&mut payload).await?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+
+        match opcode {
+            OP_CLOSE => {
+                info!("WebSocket closed by client");
+                write_frame(&mut io, true, OP_CLOSE, &payload).await?;
+                return Ok(());
+            }
+            OP_PING => write_frame(&mut io, true, OP_PONG, &payload).await?,
+            OP_PONG => (),
+            // Continuation, text and binary frames go back as they came.
+            _ => write_frame(&mut io, fin, opcode, &payload).await?,
+        }
+    }
+}
+
+/// Writes an unmasked frame, as servers send them.
+async fn write_frame<T>(io: &mut T, fin: bool, opcode: u8, payload: &[u8]) -> Result<()>
+where T: AsyncWrite + Unpin {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(if fin { 0x80 } else { 0 } | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    io.write_all(&frame).await?;
+    Ok(())
+}
@@ -0,0 +1,391 @@
+mod common;
+
+use std::time::Duration;
+use common::{RawResponse, TestServer};
+use http_server_starter_rust::basic_http_server::BasicHttpServer;
+use http_server_starter_rust::server_config::RouteTimeout;
+use http_server_starter_rust::{ServerConfig, ServerError};
+use pretty_assertions::assert_eq;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[tokio::test]
+async fn root_is_ok() {
+    let server = TestServer::start().await;
+    let resp = server.get("/").await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.text(), "");
+}
+
+#[tokio::test]
+async fn echo_sends_back_the_path() {
+    let server = TestServer::start().await;
+    let resp = server.get("/echo/abc").await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("text/plain"));
+    assert_eq!(resp.header("content-length"), Some("3"));
+    assert_eq!(resp.text(), "abc");
+}
+
+#[tokio::test]
+async fn user_agent_is_sent_back() {
+    let server = TestServer::start().await;
+    let resp = server.request("GET", "/user-agent", &[("User-Agent", "test/1.0")]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.text(), "test/1.0");
+}
+
+#[tokio::test]
+async fn missing_file_is_not_found() {
+    let server = TestServer::start().await;
+    let resp = server.get("/files/missing.txt").await;
+    assert_eq!(resp.status, 404);
+}
+
+#[tokio::test]
+async fn file_is_served() {
+    let server = TestServer::start().await;
+    server.write("hello.txt", "hello world");
+    let resp = server.get("/files/hello.txt").await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("application/octet-stream"));
+    assert_eq!(resp.text(), "hello world");
+}
+
+#[tokio::test]
+async fn unknown_path_is_not_found() {
+    let server = TestServer::start().await;
+    let resp = server.get("/nowhere").await;
+    assert_eq!(resp.status, 404);
+}
+
+#[tokio::test]
+async fn header_names_are_lower_case_by_default() {
+    let server = TestServer::start().await;
+    let raw = server.send("GET /echo/abc HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    let raw = String::from_utf8(raw).unwrap();
+    assert!(raw.contains("\r\ncontent-length: 3\r\n"), "{raw}");
+}
+
+#[tokio::test]
+async fn header_names_are_title_cased_when_asked() {
+    let server = TestServer::with_config(|config| config.title_case_headers = true).await;
+    let raw = server.send("GET /echo/abc HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    let raw = String::from_utf8(raw).unwrap();
+    assert_eq!(raw, "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 3\r\n\r\nabc");
+}
+
+#[tokio::test]
+async fn teapot_is_off_by_default() {
+    let server = TestServer::start().await;
+    assert_eq!(server.get("/teapot").await.status, 404);
+}
+
+#[tokio::test]
+async fn teapot_refuses_to_brew_coffee() {
+    let server = TestServer::with_config(|config| config.teapot = true).await;
+    let resp = server.get("/teapot").await;
+    assert_eq!(resp.status, 418);
+    assert_eq!(resp.header("content-type"), Some("text/plain"));
+    assert!(resp.text().contains("teapot"));
+}
+
+#[tokio::test]
+async fn echo_is_compressed_when_gzip_is_preferred() {
+    let server = TestServer::start().await;
+    let path = format!("/echo/{}", "a".repeat(200));
+    let resp = server.request("GET", &path, &[("Accept-Encoding", "identity;q=0.5, gzip;q=0.9")]).await;
+    assert_eq!(resp.header("content-encoding"), Some("gzip"));
+}
+
+#[tokio::test]
+async fn echo_is_not_compressed_when_identity_is_preferred() {
+    let server = TestServer::start().await;
+    let text = "a".repeat(200);
+    let resp = server.request("GET", &format!("/echo/{text}"),
+                              &[("Accept-Encoding", "gzip;q=0.1, identity;q=0.9")]).await;
+    assert_eq!(resp.header("content-encoding"), None);
+    assert_eq!(resp.text(), text);
+}
+
+#[tokio::test]
+async fn verbose_404_names_the_request() {
+    let server = TestServer::with_config(|config| config.verbose_404 = true).await;
+    let resp = server.get("/nowhere/%0D%0Aevil?x=1").await;
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.text(), "404 Not Found\nGET /nowhere/%0D%0Aevil\n");
+    assert_eq!(resp.header("content-length"), Some(resp.body.len().to_string().as_str()));
+
+    let resp = server.get("/files/missing.txt").await;
+    assert_eq!(resp.text(), "404 Not Found\nGET /files/missing.txt\n");
+}
+
+#[tokio::test]
+async fn connection_requests_count_up_on_a_kept_alive_connection() {
+    let server = TestServer::with_config(|config| config.debug_headers = true).await;
+    let raw = server.send("GET /echo/a HTTP/1.1\r\nHost: x\r\n\r\n\
+                           GET /echo/b HTTP/1.1\r\nHost: x\r\n\r\n\
+                           GET /echo/c HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    // Each response's one byte body is followed by the next response.
+    let mut rest = raw;
+    for (count, text) in [("1", "a"), ("2", "b"), ("3", "c")] {
+        let resp = RawResponse::parse(&rest);
+        assert_eq!(resp.header("x-connection-requests"), Some(count));
+        assert_eq!(&resp.body[..1], text.as_bytes());
+        rest = resp.body[1..].to_vec();
+    }
+
+    // A new connection starts over.
+    assert_eq!(server.get("/").await.header("x-connection-requests"), Some("1"));
+}
+
+#[tokio::test]
+async fn no_connection_requests_header_by_default() {
+    let server = TestServer::start().await;
+    assert_eq!(server.get("/").await.header("x-connection-requests"), None);
+}
+
+#[tokio::test]
+async fn status_endpoint_answers_with_final_statuses_only() {
+    let server = TestServer::with_config(|config| config.test_endpoints = true).await;
+    let resp = server.get("/status/503").await;
+    assert_eq!(resp.status, 503);
+    assert_eq!(resp.text(), "");
+    assert_eq!(server.get("/status/204").await.status, 204);
+    assert_eq!(server.get("/status/100").await.status, 400);
+    assert_eq!(server.get("/status/600").await.status, 400);
+    assert_eq!(server.get("/status/abc").await.status, 400);
+}
+
+#[tokio::test]
+async fn delay_endpoint_is_capped_at_a_minute() {
+    let server = TestServer::with_config(|config| config.test_endpoints = true).await;
+    assert_eq!(server.get("/delay/0").await.status, 200);
+    assert_eq!(server.get("/delay/61").await.status, 400);
+    assert_eq!(server.get("/delay/18446744073709551615").await.status, 400);
+}
+
+#[tokio::test]
+async fn debug_config_is_served_as_json_to_local_clients_only() {
+    let server = TestServer::with_config(|config| {
+        config.debug = true;
+        config.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+    }).await;
+    let resp = server.get("/debug/config").await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("application/json"));
+    let json = resp.text();
+    assert!(json.starts_with('{') && json.ends_with('}'), "{json}");
+    assert!(json.contains(&format!("\"directory\":\"{}\"", server.dir.display())), "{json}");
+    assert!(json.contains("\"debug\":true"), "{json}");
+
+    // A local proxy forwarding for someone else doesn't make them local.
+    let resp = server.request("GET", "/debug/config", &[("X-Forwarded-For", "203.0.113.5")]).await;
+    assert_eq!(resp.status, 404);
+}
+
+#[tokio::test]
+async fn debug_config_is_off_by_default() {
+    let server = TestServer::start().await;
+    assert_eq!(server.get("/debug/config").await.status, 404);
+}
+
+#[tokio::test]
+async fn header_without_a_colon_is_a_bad_request() {
+    let server = TestServer::start().await;
+    let raw = server.send("GET / HTTP/1.1\r\nHost: x\r\nNot a header\r\n\r\n").await;
+    let resp = RawResponse::parse(&raw);
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.header("connection"), Some("close"));
+}
+
+#[tokio::test]
+async fn errors_are_json_for_clients_asking_for_it() {
+    let server = TestServer::start().await;
+    let resp = server.request("GET", "/files/missing.txt", &[("Accept", "text/html, application/json;q=0.9")]).await;
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.header("content-type"), Some("application/json"));
+    assert_eq!(resp.text(), r#"{"error":"not found","status":404}"#);
+
+    // Refusing JSON outright gets the plain text body.
+    let resp = server.request("GET", "/files/missing.txt", &[("Accept", "application/json;q=0")]).await;
+    assert_eq!(resp.header("content-type"), Some("text/plain"));
+    assert_eq!(resp.text(), "404 Not Found\n");
+}
+
+#[tokio::test]
+async fn unsupported_version_is_refused() {
+    let server = TestServer::start().await;
+    let raw = server.send("GET / HTTP/2.0\r\nHost: x\r\n\r\n").await;
+    let resp = RawResponse::parse(&raw);
+    assert_eq!(resp.status, 505);
+    assert!(raw.starts_with(b"HTTP/1.1 505 "));
+}
+
+#[tokio::test]
+async fn http10_is_answered_in_kind() {
+    let server = TestServer::start().await;
+    let raw = server.send("GET /echo/abc HTTP/1.0\r\n\r\n").await;
+    assert!(raw.starts_with(b"HTTP/1.0 200 OK\r\n"));
+    assert_eq!(RawResponse::parse(&raw).text(), "abc");
+}
+
+#[tokio::test]
+async fn http11_needs_a_host() {
+    let server = TestServer::start().await;
+    let raw = server.send("GET /echo/abc HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+    assert_eq!(RawResponse::parse(&raw).status, 400);
+
+    let raw = server.send("GET /echo/abc HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    let resp = RawResponse::parse(&raw);
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.text(), "abc");
+}
+
+#[tokio::test]
+async fn binding_a_taken_port_is_a_bind_error() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = taken.local_addr().unwrap().to_string();
+    let config = ServerConfig { reuse_address: false, ..ServerConfig::default() };
+    let err = BasicHttpServer::new(&addr, config).await.err().expect("port was bound twice");
+    assert!(matches!(&err, ServerError::Bind { addr: bound, .. } if *bound == addr), "{err:?}");
+}
+
+#[tokio::test]
+async fn slow_route_times_out_with_408() {
+    let server = TestServer::with_config(|config| {
+        config.test_endpoints = true;
+        config.route_timeouts = vec![
+            RouteTimeout { prefix: "/delay".to_string(), timeout: Duration::from_millis(200) },
+            RouteTimeout { prefix: "/delay/1".to_string(), timeout: Duration::from_secs(5) },
+        ];
+    }).await;
+    let raw = server.send("GET /delay/2 HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    let resp = RawResponse::parse(&raw);
+    assert_eq!(resp.status, 408);
+    assert_eq!(resp.header("connection"), Some("close"));
+
+    // The longer timeout of the more specific prefix applies.
+    assert_eq!(server.get("/delay/1").await.status, 200);
+}
+
+#[tokio::test]
+async fn websocket_echoes_a_text_frame() {
+    let server = TestServer::start().await;
+    let mut stream = server.connect().await;
+    stream.write_all(b"GET /ws HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n")
+        .await.unwrap();
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    let resp = RawResponse::parse(&head);
+    assert_eq!(resp.status, 101);
+    assert_eq!(resp.header("sec-websocket-accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+    let mask = [0x37, 0xfa, 0x21, 0x3d];
+    let mut frame = vec![0x81, 0x80 | 5];
+    frame.extend_from_slice(&mask);
+    frame.extend(b"hello".iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    stream.write_all(&frame).await.unwrap();
+    let mut echoed = [0; 7];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"\x81\x05hello");
+
+    stream.write_all(&[0x88, 0x80, 0, 0, 0, 0]).await.unwrap();
+    assert_eq!(common::read_to_close(&mut stream).await, b"\x88\x00");
+}
+
+#[tokio::test]
+async fn root_is_ok_over_http2_with_prior_knowledge() {
+    let server = TestServer::with_config(|config| config.http2 = true).await;
+    let (mut client, connection) = h2::client::handshake(server.connect().await).await.unwrap();
+    tokio::spawn(connection);
+
+    let request = http::Request::get("http://localhost/").body(()).unwrap();
+    let (response, _) = client.send_request(request, true).unwrap();
+    let response = response.await.unwrap();
+    assert_eq!(response.status(), http::StatusCode::OK);
+}
+
+#[tokio::test]
+async fn trickled_request_line_times_out_with_408() {
+    let server = TestServer::with_config(|config| config.header_timeout = Duration::from_millis(500)).await;
+    let (reader, mut writer) = server.connect().await.into_split();
+    let started = std::time::Instant::now();
+    // A byte at a time keeps the connection busy, but not past the
+    // deadline for the whole of the headers.
+    let trickle = async {
+        for byte in b"GET /aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa" {
+            writer.write_all(&[*byte]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    tokio::select! {
+        _ = trickle => panic!("server still waiting after the whole request line"),
+        readable = reader.readable() => readable.unwrap(),
+    }
+    let mut stream = reader.reunite(writer).unwrap();
+    let raw = common::read_to_close(&mut stream).await;
+    assert_eq!(RawResponse::parse(&raw).status, 408);
+    assert!(started.elapsed() < Duration::from_millis(1500));
+}
+
+#[tokio::test]
+async fn head_has_the_length_of_the_compressed_get() {
+    let server = TestServer::start().await;
+    let path = format!("/echo/{}", "a".repeat(300));
+    let gzip = [("Accept-Encoding", "gzip")];
+    let get = server.request("GET", &path, &gzip).await;
+    assert_eq!(get.header("content-encoding"), Some("gzip"));
+    assert_eq!(get.header("content-length"), Some(get.body.len().to_string().as_str()));
+
+    // Anything after the headers would be read as the next response.
+    let raw = server.send(format!("HEAD {path} HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n\
+                                   GET /echo/next HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")).await;
+    let head = RawResponse::parse(&raw);
+    assert_eq!(head.status, 200);
+    assert_eq!(head.header("content-encoding"), Some("gzip"));
+    assert_eq!(head.header("content-length"), get.header("content-length"));
+    assert_eq!(RawResponse::parse(&head.body).text(), "next");
+}
+
+#[tokio::test]
+async fn deflate_is_zlib_unless_raw_is_asked_for() {
+    let text = "a".repeat(300);
+    let path = format!("/echo/{text}");
+    let deflate = [("Accept-Encoding", "deflate")];
+
+    let server = TestServer::start().await;
+    let resp = server.request("GET", &path, &deflate).await;
+    assert_eq!(resp.header("content-encoding"), Some("deflate"));
+    let mut decoded = String::new();
+    std::io::Read::read_to_string(&mut flate2::read::ZlibDecoder::new(&resp.body[..]), &mut decoded).unwrap();
+    assert_eq!(decoded, text);
+
+    let server = TestServer::with_config(|config| config.deflate_raw = true).await;
+    let resp = server.request("GET", &path, &deflate).await;
+    assert_eq!(resp.header("content-encoding"), Some("deflate"));
+    let mut decoded = String::new();
+    std::io::Read::read_to_string(&mut flate2::read::DeflateDecoder::new(&resp.body[..]), &mut decoded).unwrap();
+    assert_eq!(decoded, text);
+}
+
+#[tokio::test]
+async fn server_stops_after_its_last_request() {
+    let config = ServerConfig { max_total_requests: Some(2), ..ServerConfig::default() };
+    let server = BasicHttpServer::new("127.0.0.1:0", config).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut run = tokio::spawn(async move { server.run().await });
+
+    for text in ["one", "two"] {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("GET /echo/{text} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").as_bytes())
+            .await.unwrap();
+        assert_eq!(RawResponse::parse(&common::read_to_close(&mut stream).await).text(), text);
+    }
+    tokio::time::timeout(Duration::from_secs(5), &mut run).await
+        .expect("server still running after its last request")
+        .unwrap()
+        .unwrap();
+}
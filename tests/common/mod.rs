@@ -0,0 +1,166 @@
+//! Runs a server on an ephemeral port for a test, along with a directory
+//! of its own to serve, and talks to it over raw TCP.
+
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use http_server_starter_rust::basic_http_server::BasicHttpServer;
+use http_server_starter_rust::ServerConfig;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+
+/// Longest a test waits on the server before giving up on a response.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server running in the background, stopped and its directory removed
+/// when dropped.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub dir: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Serves a fresh, empty directory with the default configuration.
+    pub async fn start() -> TestServer {
+        TestServer::with_config(|_| ()).await
+    }
+
+    /// Serves a fresh, empty directory, with `configure` adjusting the
+    /// default configuration first.
+    pub async fn with_config(configure: impl FnOnce(&mut ServerConfig)) -> TestServer {
+        TestServer::with_setup(configure, |_| ()).await
+    }
+
+    /// Like [`TestServer::with_config`], with `setup` registering handlers
+    /// and the like on the server before it starts.
+    pub async fn with_setup(configure: impl FnOnce(&mut ServerConfig),
+                            setup: impl FnOnce(&mut BasicHttpServer)) -> TestServer {
+        let dir = temp_dir();
+        let mut config = ServerConfig {
+            dir: dir.to_str().unwrap().to_string(),
+            ..ServerConfig::default()
+        };
+        configure(&mut config);
+
+        let mut server = BasicHttpServer::new("127.0.0.1:0", config).await.unwrap();
+        setup(&mut server);
+        let addr = server.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            server.run().await.unwrap();
+        });
+        TestServer { addr, dir, task }
+    }
+
+    /// Path of `name` in the served directory.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Writes a file into the served directory.
+    pub fn write(&self, name: &str, contents: impl AsRef<[u8]>) {
+        std::fs::write(self.path(name), contents).unwrap();
+    }
+
+    pub async fn connect(&self) -> TcpStream {
+        TcpStream::connect(self.addr).await.unwrap()
+    }
+
+    /// Sends `request` as is on a new connection and returns everything
+    /// the server writes until it closes the connection, so the request
+    /// should ask for that.
+    pub async fn send(&self, request: impl AsRef<[u8]>) -> Vec<u8> {
+        let mut stream = self.connect().await;
+        stream.write_all(request.as_ref()).await.unwrap();
+        read_to_close(&mut stream).await
+    }
+
+    /// Sends a bodiless request for `path` and parses the response.
+    pub async fn request(&self, method: &str, path: &str, headers: &[(&str, &str)]) -> RawResponse {
+        let mut request = format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n");
+        for (name, value) in headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        request.push_str("Connection: close\r\n\r\n");
+        RawResponse::parse(&self.send(request).await)
+    }
+
+    pub async fn get(&self, path: &str) -> RawResponse {
+        self.request("GET", path, &[]).await
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Reads until the server closes the connection.
+pub async fn read_to_close(stream: &mut TcpStream) -> Vec<u8> {
+    let mut response = vec![];
+    tokio::time::timeout(READ_TIMEOUT, stream.read_to_end(&mut response))
+        .await
+        .expect("server didn't close the connection")
+        .unwrap();
+    response
+}
+
+/// A directory under the system one that no other test uses.
+fn temp_dir() -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let dir = std::env::temp_dir().join(format!("http-server-test-{}-{}",
+                                                std::process::id(),
+                                                NEXT.fetch_add(1, Ordering::Relaxed)));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A single HTTP/1.1 response as written on the wire.
+#[derive(Debug)]
+pub struct RawResponse {
+    pub status: u16,
+    /// Header names in lower case, in the order sent.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl RawResponse {
+    /// Splits a response into its parts, taking the body to be everything
+    /// after the header section.
+    pub fn parse(raw: &[u8]) -> RawResponse {
+        let end = raw.windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .unwrap_or_else(|| panic!("no end of headers in {:?}", String::from_utf8_lossy(raw)));
+        let head = std::str::from_utf8(&raw[..end]).unwrap();
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap()
+            .split(' ')
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .expect("malformed status line");
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line.split_once(':').expect("malformed header line");
+                (name.to_ascii_lowercase(), value.trim().to_string())
+            })
+            .collect();
+        RawResponse { status, headers, body: raw[end + 4..].to_vec() }
+    }
+
+    /// Value of the first header called `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> &str {
+        std::str::from_utf8(&self.body).unwrap()
+    }
+}
@@ -0,0 +1,478 @@
+mod common;
+
+use common::{RawResponse, TestServer};
+use http_server_starter_rust::server_config::{Mount, VirtualHost};
+use http_server_starter_rust::InMemory;
+use pretty_assertions::assert_eq;
+
+#[tokio::test]
+async fn not_modified_has_validators_and_no_body() {
+    let server = TestServer::start().await;
+    server.write("a.txt", "hello");
+    let etag = server.get("/files/a.txt").await.header("etag").unwrap().to_string();
+
+    // A request pipelined after the 304 would be corrupted by any body bytes.
+    let raw = server.send(format!("GET /files/a.txt HTTP/1.1\r\nHost: x\r\nIf-None-Match: {etag}\r\n\r\n\
+                                   GET /echo/next HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")).await;
+    let not_modified = RawResponse::parse(&raw);
+    assert_eq!(not_modified.status, 304);
+    assert_eq!(not_modified.header("etag"), Some(etag.as_str()));
+    assert!(not_modified.header("last-modified").is_some());
+    assert_eq!(not_modified.header("content-length"), None);
+    assert!(not_modified.body.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+    let next = RawResponse::parse(&not_modified.body);
+    assert_eq!(next.text(), "next");
+}
+
+#[tokio::test]
+async fn dotfiles_are_hidden_by_default() {
+    let server = TestServer::start().await;
+    server.write(".foo", "foo");
+    assert_eq!(server.get("/files/.foo").await.status, 404);
+}
+
+#[tokio::test]
+async fn denied_dotfiles_stay_hidden_when_others_are_served() {
+    let server = TestServer::with_config(|config| {
+        config.serve_hidden = true;
+        config.denied_dotfiles = vec![".env".to_string(), ".git".to_string()];
+    }).await;
+    server.write(".foo", "foo");
+    server.write(".env", "SECRET=1");
+    std::fs::create_dir(server.path(".git")).unwrap();
+    server.write(".git/config", "[core]");
+
+    let resp = server.get("/files/.foo").await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.text(), "foo");
+    assert_eq!(server.get("/files/.env").await.status, 404);
+    assert_eq!(server.get("/files/.git/config").await.status, 404);
+}
+
+#[tokio::test]
+async fn deny_globs_hide_matching_files() {
+    let server = TestServer::with_config(|config| config.deny_globs = vec!["*.env".to_string()]).await;
+    server.write("prod.env", "SECRET=1");
+    std::fs::create_dir(server.path("conf")).unwrap();
+    server.write("conf/dev.env", "SECRET=2");
+    server.write("a.txt", "hello");
+
+    assert_eq!(server.get("/files/prod.env").await.status, 404);
+    assert_eq!(server.get("/files/conf/dev.env").await.status, 404);
+    assert_eq!(server.get("/files/a.txt").await.text(), "hello");
+}
+
+#[tokio::test]
+async fn early_hints_come_before_the_response() {
+    let server = TestServer::with_config(|config| {
+        config.early_hints = vec!["/files=</style.css>; rel=preload; as=style".parse().unwrap()];
+    }).await;
+    server.write("a.txt", "hello");
+
+    let raw = server.send("GET /files/a.txt HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    let hints = RawResponse::parse(&raw);
+    assert_eq!(hints.status, 103);
+    assert_eq!(hints.header("link"), Some("</style.css>; rel=preload; as=style"));
+    assert_eq!(hints.header("content-length"), None);
+    assert!(raw.starts_with(b"HTTP/1.1 103 Early Hints\r\n"));
+
+    // The final response follows the hints' blank line directly.
+    let resp = RawResponse::parse(&hints.body);
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.text(), "hello");
+}
+
+#[tokio::test]
+async fn no_early_hints_for_http10() {
+    let server = TestServer::with_config(|config| {
+        config.early_hints = vec!["/files=</style.css>; rel=preload".parse().unwrap()];
+    }).await;
+    server.write("a.txt", "hello");
+
+    let resp = RawResponse::parse(&server.send("GET /files/a.txt HTTP/1.0\r\n\r\n").await);
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.text(), "hello");
+}
+
+#[tokio::test]
+async fn range_in_another_unit_gets_the_whole_file() {
+    let server = TestServer::start().await;
+    server.write("a.txt", "hello world");
+
+    let resp = server.request("GET", "/files/a.txt", &[("Range", "items=0-4")]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-range"), None);
+    assert_eq!(resp.text(), "hello world");
+
+    let resp = server.request("GET", "/files/a.txt", &[("Range", "bytes=0-4")]).await;
+    assert_eq!(resp.status, 206);
+    assert_eq!(resp.text(), "hello");
+}
+
+#[tokio::test]
+async fn range_of_the_whole_file_is_partial_content() {
+    let server = TestServer::start().await;
+    server.write("a.txt", "hello world");
+
+    let resp = server.request("GET", "/files/a.txt", &[("Range", "bytes=0-")]).await;
+    assert_eq!(resp.status, 206);
+    assert_eq!(resp.header("content-range"), Some("bytes 0-10/11"));
+    assert_eq!(resp.header("content-length"), Some("11"));
+    assert_eq!(resp.text(), "hello world");
+}
+
+#[tokio::test]
+async fn if_modified_since_far_in_the_future_is_ignored() {
+    let server = TestServer::start().await;
+    server.write("a.txt", "hello");
+    let resp = server.request("GET", "/files/a.txt",
+                              &[("If-Modified-Since", "Sun, 06 Nov 999999999999999 08:49:37 GMT")]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.text(), "hello");
+}
+
+#[tokio::test]
+async fn if_none_match_takes_precedence_over_if_modified_since() {
+    let server = TestServer::start().await;
+    server.write("a.txt", "hello");
+    let last_modified = server.get("/files/a.txt").await.header("last-modified").unwrap().to_string();
+
+    let resp = server.request("GET", "/files/a.txt", &[("If-Modified-Since", &last_modified)]).await;
+    assert_eq!(resp.status, 304);
+
+    // The date alone would make it a 304, but the ETag doesn't match.
+    let resp = server.request("GET", "/files/a.txt",
+                              &[("If-None-Match", "\"other\""), ("If-Modified-Since", &last_modified)]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.text(), "hello");
+}
+
+#[tokio::test]
+async fn mapped_file_matches_read_file() {
+    let contents: Vec<u8> = (0..200_000u32).map(|n| (n * 7 % 251) as u8).collect();
+    let read = TestServer::start().await;
+    read.write("big.bin", &contents);
+    let mapped = TestServer::with_config(|config| config.mmap_min_size = Some(1024)).await;
+    mapped.write("big.bin", &contents);
+    mapped.write("small.bin", "below the threshold");
+    // The ETags only agree for files modified at the same time.
+    let modified = std::fs::metadata(read.path("big.bin")).unwrap().modified().unwrap();
+    std::fs::File::options().write(true).open(mapped.path("big.bin")).unwrap().set_modified(modified).unwrap();
+
+    let expected = read.get("/files/big.bin").await;
+    let resp = mapped.get("/files/big.bin").await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-length"), Some("200000"));
+    assert_eq!(resp.header("etag"), expected.header("etag"));
+    assert!(resp.body == contents);
+
+    assert_eq!(mapped.get("/files/small.bin").await.text(), "below the threshold");
+
+    let head = mapped.request("HEAD", "/files/big.bin", &[]).await;
+    assert_eq!(head.header("content-length"), Some("200000"));
+    assert!(head.body.is_empty());
+}
+
+#[tokio::test]
+async fn empty_file_is_served_without_a_body() {
+    let server = TestServer::start().await;
+    server.write("empty", "");
+
+    // A pipelined request is only answered if the server doesn't wait on
+    // body bytes for the empty file.
+    let raw = server.send("GET /files/empty HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n\
+                           GET /echo/next HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    let resp = RawResponse::parse(&raw);
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-type"), Some("application/octet-stream"));
+    assert_eq!(resp.header("content-length"), Some("0"));
+    assert_eq!(resp.header("content-encoding"), None);
+    assert_eq!(RawResponse::parse(&resp.body).text(), "next");
+}
+
+#[tokio::test]
+async fn ranges_are_served_uncompressed() {
+    let server = TestServer::start().await;
+    // Large enough that the whole file would be gzip-streamed.
+    let contents = "abcdefghij".repeat(200_000);
+    server.write("big.txt", &contents);
+
+    let whole = server.request("GET", "/files/big.txt", &[("Accept-Encoding", "gzip")]).await;
+    assert_eq!(whole.header("content-encoding"), Some("gzip"));
+
+    let resp = server.request("GET", "/files/big.txt", &[("Accept-Encoding", "gzip"), ("Range", "bytes=5-14")]).await;
+    assert_eq!(resp.status, 206);
+    assert_eq!(resp.header("content-encoding"), None);
+    assert_eq!(resp.header("content-range"), Some("bytes 5-14/2000000"));
+    assert_eq!(resp.text(), "fghijabcde");
+}
+
+#[tokio::test]
+async fn precompressed_variant_follows_q_values() {
+    let server = TestServer::with_config(|config| config.precompressed = true).await;
+    server.write("a.txt", "plain");
+    server.write("a.txt.gz", "gzipped");
+    server.write("a.txt.br", "brotli");
+
+    let resp = server.request("GET", "/files/a.txt", &[("Accept-Encoding", "br;q=0.1, gzip;q=0.9")]).await;
+    assert_eq!(resp.header("content-encoding"), Some("gzip"));
+    assert_eq!(resp.text(), "gzipped");
+}
+
+#[tokio::test]
+async fn gzip_streamed_file_has_its_own_etag() {
+    let server = TestServer::start().await;
+    server.write("big.txt", "abcdefghij".repeat(200_000));
+
+    let plain = server.get("/files/big.txt").await;
+    let plain_etag = plain.header("etag").unwrap().to_string();
+    let gzipped = server.request("GET", "/files/big.txt", &[("Accept-Encoding", "gzip")]).await;
+    let gzip_etag = gzipped.header("etag").unwrap().to_string();
+    assert_eq!(gzipped.header("content-encoding"), Some("gzip"));
+    assert_eq!(gzipped.header("vary"), Some("Accept-Encoding"));
+    assert_ne!(gzip_etag, plain_etag);
+
+    let resp = server.request("GET", "/files/big.txt",
+                              &[("Accept-Encoding", "gzip"), ("If-None-Match", &gzip_etag)]).await;
+    assert_eq!(resp.status, 304);
+    // The plain file's validator doesn't stand for the gzipped one.
+    let resp = server.request("GET", "/files/big.txt",
+                              &[("Accept-Encoding", "gzip"), ("If-None-Match", &plain_etag)]).await;
+    assert_eq!(resp.status, 200);
+}
+
+#[tokio::test]
+async fn percent_encoded_path_is_decoded() {
+    let server = TestServer::start().await;
+    server.write("my file.txt", "spaced out");
+    let resp = server.get("/files/my%20file.txt").await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.text(), "spaced out");
+
+    assert_eq!(server.get("/files/my%2").await.status, 400);
+    assert_eq!(server.get("/files/my%zzfile.txt").await.status, 400);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn symlink_out_of_the_directory_is_refused_when_not_followed() {
+    let server = TestServer::with_config(|config| config.follow_symlinks = false).await;
+    let outside = server.dir.with_extension("outside");
+    std::fs::write(&outside, "secret").unwrap();
+    std::os::unix::fs::symlink(&outside, server.path("link.txt")).unwrap();
+    std::fs::create_dir(server.path("sub")).unwrap();
+    server.write("sub/a.txt", "hello");
+    std::os::unix::fs::symlink(server.path("sub"), server.path("linked")).unwrap();
+
+    let file = server.get("/files/link.txt").await;
+    let through_dir = server.get("/files/linked/a.txt").await;
+    std::fs::remove_file(&outside).unwrap();
+    assert_eq!(file.status, 404);
+    assert_eq!(through_dir.status, 404);
+    assert_eq!(server.get("/files/sub/a.txt").await.text(), "hello");
+}
+
+#[tokio::test]
+async fn stored_content_type_is_read_before_a_gzip_stream_takes_the_last_open_file() {
+    let server = TestServer::with_config(|config| {
+        config.max_open_files = Some(1);
+        config.store_content_type = true;
+    }).await;
+    server.write("big.txt", "abcdefghij".repeat(200_000));
+    server.write("big.txt.meta", "text/plain");
+
+    let resp = server.request("GET", "/files/big.txt", &[("Accept-Encoding", "gzip")]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-encoding"), Some("gzip"));
+    assert_eq!(resp.header("content-type"), Some("text/plain"));
+    // The permit held by the stream was given back.
+    assert_eq!(server.get("/files/big.txt").await.status, 200);
+}
+
+/// Splits a chunked body into its data and whatever trailer section
+/// follows the last chunk.
+fn dechunk(mut body: &[u8]) -> (Vec<u8>, String) {
+    let mut data = vec![];
+    loop {
+        let eol = body.windows(2).position(|w| w == b"\r\n").unwrap();
+        let size = usize::from_str_radix(std::str::from_utf8(&body[..eol]).unwrap(), 16).unwrap();
+        body = &body[eol + 2..];
+        if size == 0 {
+            return (data, String::from_utf8(body.to_vec()).unwrap());
+        }
+        data.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+#[tokio::test]
+async fn streamed_file_ends_with_its_md5_trailer() {
+    let server = TestServer::start().await;
+    let contents = "abcdefghij".repeat(200_000);
+    server.write("big.txt", &contents);
+
+    // With gzip only as a transfer coding, the digest is of the file.
+    let resp = server.request("GET", "/files/big.txt", &[("TE", "gzip, trailers")]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("transfer-encoding"), Some("gzip, chunked"));
+    assert_eq!(resp.header("trailer"), Some("Content-MD5"));
+    let (data, trailers) = dechunk(&resp.body);
+    let mut decoded = String::new();
+    std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&data[..]), &mut decoded).unwrap();
+    assert_eq!(decoded, contents);
+    assert_eq!(trailers, "Content-MD5: uoo3QsDJu3sbduunYGiZLA==\r\n\r\n");
+}
+
+#[tokio::test]
+async fn concurrent_gets_share_a_single_open_file() {
+    let server = TestServer::with_config(|config| config.max_open_files = Some(1)).await;
+    let big = "abcdefghij".repeat(200_000);
+    server.write("big.txt", &big);
+    server.write("a.txt", "hello");
+
+    let gzip = [("Accept-Encoding", "gzip")];
+    let (streamed, whole, small, other) = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        tokio::join!(server.request("GET", "/files/big.txt", &gzip),
+                     server.get("/files/big.txt"),
+                     server.get("/files/a.txt"),
+                     server.get("/files/a.txt"))
+    }).await.expect("requests waiting on each other's files");
+    assert_eq!(streamed.header("content-encoding"), Some("gzip"));
+    assert_eq!(whole.text(), big);
+    assert_eq!(small.text(), "hello");
+    assert_eq!(other.text(), "hello");
+}
+
+#[tokio::test]
+async fn te_gzip_compresses_only_the_transfer() {
+    let server = TestServer::start().await;
+    let contents = "abcdefghij".repeat(200_000);
+    server.write("big.txt", &contents);
+    server.write("a.txt", "hello");
+
+    let resp = server.request("GET", "/files/big.txt", &[("TE", "gzip")]).await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("transfer-encoding"), Some("gzip, chunked"));
+    assert_eq!(resp.header("content-encoding"), None);
+    assert_eq!(resp.header("content-length"), None);
+    let (data, trailers) = dechunk(&resp.body);
+    assert!(data.len() < contents.len());
+    let mut decoded = String::new();
+    std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&data[..]), &mut decoded).unwrap();
+    assert_eq!(decoded, contents);
+    assert_eq!(trailers, "\r\n");
+
+    // Small files are sent as they are.
+    let resp = server.request("GET", "/files/a.txt", &[("TE", "gzip")]).await;
+    assert_eq!(resp.header("transfer-encoding"), None);
+    assert_eq!(resp.header("content-length"), Some("5"));
+    assert_eq!(resp.text(), "hello");
+}
+
+#[tokio::test]
+async fn removed_directory_is_unavailable_rather_than_empty() {
+    let server = TestServer::start().await;
+    server.write("a.txt", "hello");
+    assert_eq!(server.get("/files/missing.txt").await.status, 404);
+
+    std::fs::remove_dir_all(&server.dir).unwrap();
+    assert_eq!(server.get("/files/a.txt").await.status, 503);
+    let raw = server.send("PUT /files/a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\
+                           Connection: close\r\n\r\nhello").await;
+    assert_eq!(RawResponse::parse(&raw).status, 503);
+    // Everything not served from the directory carries on.
+    assert_eq!(server.get("/echo/up").await.text(), "up");
+}
+
+#[tokio::test]
+async fn mounts_serve_their_own_roots() {
+    let server = TestServer::with_config(|config| {
+        config.mounts = [("/static", "css"), ("/static/img", "img")].iter().map(|(prefix, root)| {
+            let root = format!("{}/{root}", config.dir);
+            std::fs::create_dir(&root).unwrap();
+            Mount { prefix: prefix.to_string(), root }
+        }).collect();
+    }).await;
+    server.write("css/a.css", "css");
+    server.write("img/a.png", "png");
+    server.write("a.txt", "hello");
+
+    assert_eq!(server.get("/static/a.css").await.text(), "css");
+    // The longer prefix is the one that serves.
+    assert_eq!(server.get("/static/img/a.png").await.text(), "png");
+    assert_eq!(server.get("/static/img/a.css").await.status, 404);
+    assert_eq!(server.get("/files/a.txt").await.text(), "hello");
+    assert_eq!(server.get("/files/a.css").await.status, 404);
+}
+
+#[tokio::test]
+async fn vhosts_pick_the_directory_by_host() {
+    let server = TestServer::with_config(|config| {
+        let dir = format!("{}/example", config.dir);
+        std::fs::create_dir(&dir).unwrap();
+        config.vhosts = vec![VirtualHost { host: "example.com".to_string(), dir }];
+    }).await;
+    server.write("a.txt", "default");
+    server.write("example/a.txt", "example");
+
+    let get = |host: &str| server.send(format!("GET /files/a.txt HTTP/1.1\r\nHost: {host}\r\n\
+                                                Connection: close\r\n\r\n"));
+    assert_eq!(RawResponse::parse(&get("example.com").await).text(), "example");
+    assert_eq!(RawResponse::parse(&get("EXAMPLE.com:8080").await).text(), "example");
+    assert_eq!(RawResponse::parse(&get("other.com").await).text(), "default");
+}
+
+#[tokio::test]
+async fn download_names_outside_ascii_are_encoded_per_rfc_5987() {
+    let server = TestServer::start().await;
+    server.write("résumé 1.txt", "cv");
+    server.write("a.txt", "hello");
+
+    let resp = server.get("/files/r%C3%A9sum%C3%A9%201.txt?download=1").await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("content-disposition"),
+               Some("attachment; filename=\"r_sum_ 1.txt\"; filename*=UTF-8''r%C3%A9sum%C3%A9%201.txt"));
+
+    let resp = server.get("/files/a.txt?download=true").await;
+    assert_eq!(resp.header("content-disposition"), Some("attachment; filename=\"a.txt\""));
+    assert_eq!(server.get("/files/a.txt").await.header("content-disposition"), None);
+}
+
+#[tokio::test]
+async fn in_memory_files_are_served_read_only() {
+    let source = InMemory::new().file("a.txt", "hello world").file("docs/b.txt", "nested");
+    let server = TestServer::with_setup(|_| (), |server| server.file_source(source)).await;
+    // Nothing on disk stands in for the files in memory.
+    server.write("c.txt", "on disk");
+
+    let resp = server.get("/files/a.txt").await;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.text(), "hello world");
+    assert!(resp.header("etag").is_some());
+    assert_eq!(server.get("/files/docs/./b.txt").await.text(), "nested");
+    assert_eq!(server.request("GET", "/files/a.txt", &[("Range", "bytes=6-")]).await.text(), "world");
+    assert_eq!(server.get("/files/c.txt").await.status, 404);
+
+    let raw = server.send("PUT /files/a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\
+                           Connection: close\r\n\r\nnew").await;
+    let resp = RawResponse::parse(&raw);
+    assert_eq!(resp.status, 405);
+    assert_eq!(resp.header("allow"), Some("GET, HEAD"));
+    assert_eq!(server.get("/files/a.txt").await.text(), "hello world");
+}
+
+#[tokio::test]
+async fn throttled_download_takes_as_long_as_its_bandwidth_allows() {
+    let server = TestServer::with_config(|config| config.max_bandwidth = Some(100_000)).await;
+    let contents = "abcdefghij".repeat(5_000);
+    server.write("a.txt", &contents);
+
+    let started = std::time::Instant::now();
+    let resp = server.get("/files/a.txt").await;
+    let elapsed = started.elapsed();
+    assert_eq!(resp.text(), contents);
+    // 50 kB at 100 kB/s is half a second, less the tenth of a second's
+    // burst allowed up front and some slack for timer rounding.
+    assert!(elapsed >= std::time::Duration::from_millis(350), "took {elapsed:?}");
+}
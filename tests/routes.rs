@@ -0,0 +1,116 @@
+mod common;
+
+use common::{RawResponse, TestServer};
+use http::HeaderValue;
+use http_server_starter_rust::{Body, Request, Response, StatusCode};
+use pretty_assertions::assert_eq;
+
+async fn teapot(request: Request<Body>) -> Response<Vec<u8>> {
+    let brew = request.headers()
+        .get("x-brew")
+        .and_then(|brew| brew.to_str().ok())
+        .unwrap_or("tea")
+        .to_string();
+    Response::builder()
+        .status(StatusCode::IM_A_TEAPOT)
+        .header("content-length", brew.len())
+        .body(brew.into_bytes())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn route_handler_answers_its_method_and_prefix() {
+    let server = TestServer::with_setup(|_| (), |server| server.route("GET", "/teapot", teapot)).await;
+
+    let resp = server.request("GET", "/teapot", &[("X-Brew", "earl grey")]).await;
+    assert_eq!(resp.status, 418);
+    assert_eq!(resp.text(), "earl grey");
+    assert_eq!(server.get("/teapot/pot").await.status, 418);
+    assert_eq!(server.get("/teapots").await.status, 404);
+    // Other methods still get the built-in handling.
+    assert_eq!(server.request("DELETE", "/teapot", &[]).await.status, 405);
+}
+
+#[tokio::test]
+async fn route_handler_reads_the_body() {
+    let server = TestServer::with_setup(|_| (), |server| {
+        server.route("PATCH", "/count", |request: Request<Body>| async move {
+            let mut body = request.into_body();
+            let mut len = 0;
+            while let Some(chunk) = body.chunk().await {
+                len += chunk.unwrap().len();
+            }
+            // Left for the server to frame.
+            Response::new(len.to_string().into_bytes())
+        });
+    }).await;
+
+    // The next request must start right after the body for the pipelined
+    // GET to be answered.
+    let raw = server.send("PATCH /count HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello\
+                           GET /echo/next HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    let counted = RawResponse::parse(&raw);
+    assert_eq!(counted.status, 200);
+    assert_eq!(counted.header("content-length"), Some("1"));
+    assert!(counted.body.starts_with(b"5HTTP/1.1 200 OK\r\n"));
+    assert_eq!(RawResponse::parse(&counted.body[1..]).text(), "next");
+}
+
+#[tokio::test]
+async fn route_header_values_are_sent_as_bytes() {
+    let server = TestServer::with_setup(|_| (), |server| {
+        server.route("GET", "/latin1", |_: Request<Body>| async {
+            Response::builder()
+                .header("x-name", HeaderValue::from_bytes(b"caf\xe9").unwrap())
+                .body(vec![])
+                .unwrap()
+        });
+    }).await;
+
+    let raw = server.send("GET /latin1 HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    let header = b"x-name: caf\xe9\r\n";
+    assert!(raw.windows(header.len()).any(|w| w == header));
+}
+
+#[tokio::test]
+async fn route_handler_answers_over_http2() {
+    let server = TestServer::with_setup(|config| config.http2 = true,
+                                        |server| server.route("GET", "/teapot", teapot)).await;
+    let (mut client, connection) = h2::client::handshake(server.connect().await).await.unwrap();
+    tokio::spawn(connection);
+
+    let request = Request::get("http://localhost/teapot").header("x-brew", "oolong").body(()).unwrap();
+    let (response, _) = client.send_request(request, true).unwrap();
+    let response = response.await.unwrap();
+    assert_eq!(response.status(), StatusCode::IM_A_TEAPOT);
+    let mut body = response.into_body();
+    let mut text = vec![];
+    while let Some(chunk) = body.data().await {
+        text.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(text, b"oolong");
+}
+
+#[tokio::test]
+async fn hop_by_hop_headers_stay_with_the_connection() {
+    let server = TestServer::with_setup(|_| (), |server| {
+        server.route("GET", "/headers", |request: Request<Body>| async move {
+            let mut names: Vec<_> = request.headers().keys().map(|name| name.to_string()).collect();
+            names.sort();
+            Response::builder()
+                .header("transfer-encoding", "chunked")
+                .header("keep-alive", "timeout=5")
+                .body(names.join(",").into_bytes())
+                .unwrap()
+        });
+    }).await;
+
+    let raw = server.send("GET /headers HTTP/1.1\r\nHost: x\r\nConnection: x-hop, , keep-alive\r\n\
+                           Keep-Alive: timeout=5\r\nX-Hop: 1\r\nTE: trailers\r\nX-End: 1\r\n\r\n\
+                           GET /echo/next HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    let resp = RawResponse::parse(&raw);
+    assert_eq!(resp.header("transfer-encoding"), None);
+    assert_eq!(resp.header("keep-alive"), None);
+    assert_eq!(&resp.body[..10], b"host,x-end");
+    assert_eq!(RawResponse::parse(&resp.body[10..]).text(), "next");
+}
@@ -0,0 +1,340 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use common::{RawResponse, TestServer};
+use pretty_assertions::assert_eq;
+use tokio::io::AsyncWriteExt;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Log output collected in memory.
+#[derive(Clone, Default)]
+struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl Logs {
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl std::io::Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Logs {
+    type Writer = Logs;
+
+    fn make_writer(&'a self) -> Logs {
+        self.clone()
+    }
+}
+
+#[tokio::test]
+async fn huge_content_length_is_refused() {
+    let server = TestServer::start().await;
+    let raw = server.send("POST /files/huge HTTP/1.1\r\nHost: x\r\n\
+                           Content-Length: 99999999999999999\r\n\r\nabc").await;
+    let resp = RawResponse::parse(&raw);
+    assert_eq!(resp.status, 413);
+    assert_eq!(resp.header("connection"), Some("close"));
+    assert!(!server.path("huge").exists());
+}
+
+#[tokio::test]
+async fn content_length_past_max_body_size_is_refused() {
+    let server = TestServer::with_config(|config| config.max_body_size = 4).await;
+    let raw = server.send("POST /files/a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello").await;
+    assert_eq!(RawResponse::parse(&raw).status, 413);
+
+    let raw = server.send("POST /files/a HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\
+                           Connection: close\r\n\r\nhell").await;
+    assert_eq!(RawResponse::parse(&raw).status, 201);
+}
+
+#[tokio::test]
+async fn chunked_body_past_max_body_size_is_refused() {
+    let server = TestServer::with_config(|config| config.max_body_size = 4).await;
+    let raw = server.send("POST /files/a HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+                           3\r\nhel\r\n3\r\nlo!\r\n0\r\n\r\n").await;
+    let resp = RawResponse::parse(&raw);
+    assert_eq!(resp.status, 413);
+    assert_eq!(resp.header("connection"), Some("close"));
+
+    let raw = server.send("POST /echo HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+                           5\r\nhello\r\n0\r\n\r\n").await;
+    assert_eq!(RawResponse::parse(&raw).status, 413);
+}
+
+#[tokio::test]
+async fn overflowing_content_length_is_a_bad_request() {
+    let server = TestServer::start().await;
+    let raw = server.send("POST /files/huge HTTP/1.1\r\nHost: x\r\n\
+                           Content-Length: 99999999999999999999999\r\n\r\n").await;
+    assert_eq!(RawResponse::parse(&raw).status, 400);
+}
+
+#[tokio::test]
+async fn dumped_body_prefix_is_logged() {
+    let logs = Logs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_ansi(false)
+        .with_writer(logs.clone())
+        .finish();
+    // The test's runtime runs the server's tasks on this thread too.
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let server = TestServer::with_config(|config| config.dump_bodies = true).await;
+    let body = format!("first line\n{}", "x".repeat(1000));
+    let raw = server.send(format!("POST /files/a HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\
+                                   Connection: close\r\n\r\n{body}", body.len())).await;
+    assert_eq!(RawResponse::parse(&raw).status, 201);
+
+    let logs = logs.text();
+    let dumped = logs.lines()
+        .find_map(|line| line.split_once("request body: "))
+        .map(|(_, rest)| rest)
+        .expect("no body dump logged");
+    assert!(dumped.starts_with("first line\\nxxx"));
+    // Only the start of the body is logged.
+    assert!(!logs.contains(&"x".repeat(300)));
+}
+
+#[tokio::test]
+async fn get_pipelined_after_post_is_answered() {
+    let server = TestServer::start().await;
+    // Both requests arrive in one write, so the GET is already buffered
+    // while the POST's body is being read.
+    let raw = server.send("POST /files/a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello\
+                           GET /files/a.txt HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    let created = RawResponse::parse(&raw);
+    assert_eq!(created.status, 201);
+    let served = RawResponse::parse(&created.body);
+    assert_eq!(served.status, 200);
+    assert_eq!(served.text(), "hello");
+}
+
+#[tokio::test]
+async fn get_pipelined_after_chunked_post_is_answered() {
+    let server = TestServer::start().await;
+    let raw = server.send("POST /files/a.txt HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+                           3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n\
+                           GET /files/a.txt HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    let created = RawResponse::parse(&raw);
+    assert_eq!(created.status, 201);
+    let served = RawResponse::parse(&created.body);
+    assert_eq!(served.status, 200);
+    assert_eq!(served.text(), "hello");
+}
+
+/// A form upload of `files`, given as (name, contents), to `path`.
+fn form_upload(path: &str, files: &[(&str, &str)]) -> String {
+    let mut body = String::new();
+    for (name, contents) in files {
+        body.push_str(&format!("--XyZ\r\nContent-Disposition: form-data; name=\"f\"; filename=\"{name}\"\r\n\
+                                \r\n{contents}\r\n"));
+    }
+    body.push_str("--XyZ--\r\n");
+    format!("POST {path} HTTP/1.1\r\nHost: x\r\nContent-Type: multipart/form-data; boundary=XyZ\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn form_file_named_after_a_symlink_is_skipped() {
+    let server = TestServer::with_config(|config| config.follow_symlinks = false).await;
+    let outside = server.dir.with_extension("outside");
+    std::fs::write(&outside, "untouched").unwrap();
+    std::os::unix::fs::symlink(&outside, server.path("link.txt")).unwrap();
+
+    let raw = server.send(form_upload("/files/", &[("link.txt", "overwritten"), ("a.txt", "hello")])).await;
+    let resp = RawResponse::parse(&raw);
+    let target = std::fs::read_to_string(&outside).unwrap();
+    std::fs::remove_file(&outside).unwrap();
+    assert_eq!(target, "untouched");
+    assert_eq!(resp.status, 201);
+    assert_eq!(resp.text(), r#"{"files":["a.txt"]}"#);
+    assert_eq!(std::fs::read_to_string(server.path("a.txt")).unwrap(), "hello");
+}
+
+#[tokio::test]
+async fn malformed_form_is_a_bad_request() {
+    let server = TestServer::start().await;
+    let body = "--XyZ\r\nContent-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\r\nno end";
+    let raw = server.send(format!("POST /files/ HTTP/1.1\r\nHost: x\r\n\
+                                   Content-Type: multipart/form-data; boundary=XyZ\r\n\
+                                   Content-Length: {}\r\n\r\n{body}", body.len())).await;
+    let resp = RawResponse::parse(&raw);
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.header("connection"), Some("close"));
+}
+
+#[tokio::test]
+async fn form_file_that_cant_be_written_is_a_server_error() {
+    let server = TestServer::start().await;
+    std::fs::create_dir(server.path("a.txt")).unwrap();
+    let raw = server.send(form_upload("/files/", &[("a.txt", "hello")])).await;
+    assert_eq!(RawResponse::parse(&raw).status, 500);
+}
+
+#[tokio::test]
+async fn zero_length_post_creates_an_empty_file() {
+    let server = TestServer::start().await;
+    let raw = server.send("POST /files/empty HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n\
+                           GET /echo/next HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    let created = RawResponse::parse(&raw);
+    assert_eq!(created.status, 201);
+    assert_eq!(std::fs::read(server.path("empty")).unwrap(), b"");
+    assert_eq!(RawResponse::parse(&created.body).text(), "next");
+}
+
+#[tokio::test]
+async fn slow_conditional_upload_only_holds_up_its_own_file() {
+    let server = TestServer::start().await;
+    // Sends its headers and half its body, then stalls.
+    let mut stalled = server.connect().await;
+    stalled.write_all(b"PUT /files/a.txt HTTP/1.1\r\nHost: x\r\nIf-None-Match: *\r\n\
+                        Content-Length: 10\r\n\r\nhello").await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let raw = server.send("PUT /files/b.txt HTTP/1.1\r\nHost: x\r\nIf-None-Match: *\r\n\
+                           Content-Length: 5\r\nConnection: close\r\n\r\nhello").await;
+    assert_eq!(RawResponse::parse(&raw).status, 201);
+    drop(stalled);
+}
+
+#[tokio::test]
+async fn only_one_of_two_racing_if_match_writes_lands() {
+    let server = TestServer::start().await;
+    server.write("a.txt", "hello");
+    let etag = server.get("/files/a.txt").await.header("etag").unwrap().to_string();
+    // A content range writes over the file in place, so it's untouched
+    // until the body arrives.
+    let put = format!("PUT /files/a.txt HTTP/1.1\r\nHost: x\r\nIf-Match: {etag}\r\n\
+                       Content-Range: bytes 0-4/5\r\nContent-Length: 5\r\nConnection: close\r\n\r\n");
+
+    // The first write holds back its body, so it's still under way when
+    // the second one arrives with the same validator.
+    let mut first = server.connect().await;
+    first.write_all(put.as_bytes()).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let second = tokio::spawn({
+        let request = format!("{put}other");
+        let mut stream = server.connect().await;
+        async move {
+            stream.write_all(request.as_bytes()).await.unwrap();
+            common::read_to_close(&mut stream).await
+        }
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    first.write_all(b"first").await.unwrap();
+
+    let first = RawResponse::parse(&common::read_to_close(&mut first).await);
+    let second = RawResponse::parse(&second.await.unwrap());
+    assert_eq!((first.status, second.status), (204, 412));
+    assert_eq!(std::fs::read_to_string(server.path("a.txt")).unwrap(), "first");
+}
+
+#[tokio::test]
+async fn chunked_trailers_are_skipped() {
+    let server = TestServer::start().await;
+    let raw = server.send("POST /files/a.txt HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+                           5\r\nhello\r\n0\r\nX-Checksum: 1234\r\nX-Other: a\r\n\r\n\
+                           GET /files/a.txt HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    let created = RawResponse::parse(&raw);
+    assert_eq!(created.status, 201);
+    let served = RawResponse::parse(&created.body);
+    assert_eq!(served.text(), "hello");
+}
+
+#[tokio::test]
+async fn endless_trailers_are_cut_off() {
+    let server = TestServer::with_config(|config| config.max_header_size = 1024).await;
+    let mut stream = server.connect().await;
+    stream.write_all(format!("POST /files/a.txt HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+                              5\r\nhello\r\n0\r\nX-Pad: {}", "a".repeat(4096)).as_bytes()).await.unwrap();
+    // The server gives up on the request rather than waiting for the end
+    // of the trailers.
+    let raw = common::read_to_close(&mut stream).await;
+    assert_eq!(RawResponse::parse(&raw).status, 400);
+}
+
+#[tokio::test]
+async fn partial_puts_make_up_a_file() {
+    let server = TestServer::start().await;
+    let raw = server.send("PUT /files/a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\
+                           Content-Range: bytes 6-10/11\r\n\r\nworld\
+                           PUT /files/a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 6\r\n\
+                           Content-Range: bytes 0-5/11\r\nConnection: close\r\n\r\nhello ").await;
+    let first = RawResponse::parse(&raw);
+    assert_eq!(first.status, 201);
+    assert_eq!(RawResponse::parse(&first.body).status, 204);
+    assert_eq!(std::fs::read_to_string(server.path("a.txt")).unwrap(), "hello world");
+
+    let raw = server.send("PUT /files/a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\
+                           Content-Range: bytes 8-12/11\r\nConnection: close\r\n\r\nworld").await;
+    assert_eq!(RawResponse::parse(&raw).status, 400);
+}
+
+#[tokio::test]
+async fn conflicting_content_lengths_are_a_bad_request() {
+    let server = TestServer::start().await;
+    let raw = server.send("POST /files/a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\
+                           Content-Length: 6\r\n\r\nhello!").await;
+    assert_eq!(RawResponse::parse(&raw).status, 400);
+    assert!(!server.path("a").exists());
+
+    // Repeating the same length is harmless.
+    let raw = server.send("POST /files/a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\
+                           Content-Length: 5\r\nConnection: close\r\n\r\nhello").await;
+    assert_eq!(RawResponse::parse(&raw).status, 201);
+}
+
+#[tokio::test]
+async fn content_length_with_transfer_encoding_is_a_bad_request() {
+    let server = TestServer::start().await;
+    let raw = server.send("POST /files/a HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\
+                           Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n").await;
+    let resp = RawResponse::parse(&raw);
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.header("connection"), Some("close"));
+    assert!(!server.path("a").exists());
+}
+
+#[tokio::test]
+async fn content_type_sidecars_are_out_of_reach() {
+    let server = TestServer::with_config(|config| config.store_content_type = true).await;
+    let raw = server.send("POST /files/a.txt HTTP/1.1\r\nHost: x\r\nContent-Type: text/html\r\n\
+                           Content-Length: 5\r\nConnection: close\r\n\r\nhello").await;
+    assert_eq!(RawResponse::parse(&raw).status, 201);
+
+    assert_eq!(server.get("/files/a.txt.meta").await.status, 404);
+    let raw = server.send("PUT /files/a.txt.meta HTTP/1.1\r\nHost: x\r\nContent-Length: 16\r\n\
+                           Connection: close\r\n\r\napplication/evil").await;
+    assert_eq!(RawResponse::parse(&raw).status, 404);
+    let raw = server.send(form_upload("/files/", &[("a.txt.meta", "application/evil")])).await;
+    assert_eq!(RawResponse::parse(&raw).text(), r#"{"files":[]}"#);
+
+    assert_eq!(server.get("/files/a.txt").await.header("content-type"), Some("text/html"));
+}
+
+#[tokio::test]
+async fn refusal_of_a_body_being_sent_arrives() {
+    let server = TestServer::with_config(|config| config.max_body_size = 1000).await;
+    let body = "a".repeat(200_000);
+    let raw = server.send(format!("POST /files/a HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n{body}",
+                                  body.len())).await;
+    let resp = RawResponse::parse(&raw);
+    assert_eq!(resp.status, 413);
+    assert_eq!(resp.header("connection"), Some("close"));
+
+    let raw = server.send(format!("POST /files/a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\
+                                   Content-Length: 6\r\n\r\n{body}")).await;
+    assert_eq!(RawResponse::parse(&raw).status, 400);
+}